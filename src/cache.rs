@@ -0,0 +1,138 @@
+/*
+ * Walk caching
+ */
+use serde_json;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use {Base, Maildir, Options};
+
+// The results of a walk, along with what's needed to tell if they're stale.
+#[derive(Deserialize, Serialize)]
+struct Cache {
+    key: String,
+    mtimes: Vec<(PathBuf, SystemTime)>,
+    maildirs: Vec<(PathBuf, PathBuf, bool)>,
+}
+
+// Describes the bases and the options that change what a walk finds, so a
+// cache written for a different walk isn't used.
+pub(crate) fn walk_key(bases: &[Base], options: &Options) -> String {
+    let bases = bases
+        .iter()
+        .map(|b| {
+            format!("{}={:?}:{:?}", b.path.display(), b.label, b.relative_to)
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!("{};{:?};{:?};{};{};{};{};{};{}",
+            bases,
+            options.max_depth,
+            options.min_depth,
+            options.follow_symlinks,
+            options.ignore_hidden,
+            options.strict,
+            options.cur_name(),
+            options.include_mbox,
+            options.walk_tmp)
+}
+
+// Gets the mtimes of each base and the directories directly inside it.
+// Adding or removing a folder at the top of a base changes these, but one
+// added deeper down only changes the mtime of its own parent.
+fn dir_mtimes(bases: &[Base]) -> Vec<(PathBuf, SystemTime)> {
+    let mut dirs = Vec::new();
+
+    for base in bases {
+        dirs.push(base.path.clone());
+
+        if let Ok(entries) = fs::read_dir(&base.path) {
+            dirs.extend(entries
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| p.is_dir()));
+        }
+    }
+
+    // Paths that aren't valid UTF-8 can't be written to the cache, and any
+    // maildirs in them are skipped when listing anyway.
+    dirs.retain(|d| d.to_str().is_some());
+    dirs.sort();
+
+    dirs.into_iter()
+        .filter_map(|d| {
+            let mtime = fs::metadata(&d).and_then(|m| m.modified()).ok()?;
+            Some((d, mtime))
+        })
+        .collect()
+}
+
+/// Gets the maildirs found by an earlier walk of `bases` from the cache at
+/// `path`, if it was written for the same walk and none of the directories
+/// at the top of the bases have changed since.
+pub fn load(path: &Path,
+            bases: &[Base],
+            options: &Options)
+            -> Option<Vec<Maildir>> {
+    let contents = fs::read_to_string(path).ok()?;
+    let cache: Cache = serde_json::from_str(&contents).ok()?;
+
+    if cache.key != walk_key(bases, options) ||
+       cache.mtimes != dir_mtimes(bases) {
+        return None;
+    }
+
+    let maildirs = cache.maildirs
+        .into_iter()
+        .map(|(path, full_path, mbox)| {
+            Maildir {
+                path,
+                full_path,
+                initial: false,
+                count: None,
+                unread: None,
+                size: None,
+                flagged: None,
+                replied: None,
+                mbox,
+            }
+        })
+        .collect();
+
+    Some(maildirs)
+}
+
+/// Writes the maildirs found by walking `bases` to the cache at `path`.
+pub fn save(path: &Path,
+            bases: &[Base],
+            options: &Options,
+            walked: &[Maildir])
+            -> Result<(), Box<dyn Error>> {
+    let cache = Cache {
+        key: walk_key(bases, options),
+        mtimes: dir_mtimes(bases),
+        maildirs: walked
+            .iter()
+            .filter(|m| {
+                m.path.to_str().is_some() && m.full_path.to_str().is_some()
+            })
+            .map(|m| (m.path.clone(), m.full_path.clone(), m.mbox))
+            .collect(),
+    };
+
+    let written = serde_json::to_string(&cache)
+        .map_err(|e| e.to_string())
+        .and_then(|c| fs::write(path, c).map_err(|e| e.to_string()));
+
+    match written {
+        Err(e) => {
+            let message = format!("Couldn't write cache {}: {}",
+                                  path.display(),
+                                  e);
+            Err(message.into())
+        },
+        Ok(()) => Ok(()),
+    }
+}
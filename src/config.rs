@@ -0,0 +1,73 @@
+/*
+ * Config file handling
+ */
+use dirs;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml;
+
+/// The config file can hold a single base or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Bases {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for Bases {
+    fn default() -> Bases {
+        Bases::Many(vec![])
+    }
+}
+
+impl Bases {
+    /// Gets the bases as a list, however many were given.
+    pub fn to_vec(&self) -> Vec<String> {
+        match *self {
+            Bases::One(ref b)  => vec![b.clone()],
+            Bases::Many(ref b) => b.clone(),
+        }
+    }
+}
+
+/// Defaults read from the config file.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub base: Bases,
+    pub initial: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// Finds the default config file location.
+/// This is $XDG_CONFIG_HOME/mutt-maildirs/config.toml, falling back to
+/// ~/.config when $XDG_CONFIG_HOME isn't set.
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _                                => dirs::home_dir()?.join(".config"),
+    };
+
+    Some(config_home.join("mutt-maildirs").join("config.toml"))
+}
+
+/// Reads the config file at `path`.
+pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+    let contents = match fs::read_to_string(path) {
+        Err(e) => {
+            let message = format!("Couldn't read {}: {}", path.display(), e);
+            return Err(message.into());
+        },
+        Ok(c)  => c,
+    };
+
+    match toml::from_str(&contents) {
+        Err(e) => {
+            let message = format!("Couldn't parse {}: {}", path.display(), e);
+            Err(message.into())
+        },
+        Ok(c)  => Ok(c),
+    }
+}
@@ -0,0 +1,483 @@
+/*
+ * Output formatting
+ */
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{self, Path, PathBuf};
+use Maildir;
+
+/// How quotes in paths and labels are escaped, so they survive inside the
+/// quotes of the template.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Escape {
+    /// Leave quotes alone.
+    None,
+    /// Close the quotes around a backslashed quote, as a POSIX shell wants.
+    Shell,
+    /// Close the quotes around a double quoted quote, which mutt's parser
+    /// reads without any backslash escapes being involved.
+    Mutt,
+    /// Double any double quotes, as notmuch wants inside a quoted term.
+    Notmuch,
+}
+
+impl Escape {
+    /// Escapes any quotes in `s`.
+    pub fn apply(self, s: &str) -> String {
+        match self {
+            Escape::None    => s.to_owned(),
+            Escape::Shell   => s.replace('\'', "'\\''"),
+            Escape::Mutt    => s.replace('\'', "'\"'\"'"),
+            Escape::Notmuch => s.replace('"', "\"\""),
+        }
+    }
+}
+
+// The placeholders a template can hold, besides the bare {} for the path.
+const PLACEHOLDERS: &[&str] = &[
+    "path", "basename", "count", "unread", "size", "flagged", "replied",
+];
+
+/// A template for the whole output: `entry` is filled in for each maildir,
+/// between a `header` and `footer` that are written as they are.
+pub struct Template {
+    pub header: String,
+    pub entry: String,
+    pub footer: String,
+}
+
+impl Template {
+    /// Makes a template of just an entry, for output without a header or
+    /// footer.
+    pub fn entry(entry: &str) -> Template {
+        Template {
+            header: String::new(),
+            entry: entry.to_owned(),
+            footer: String::new(),
+        }
+    }
+
+    /// Parses a template file. Lines of just `%header`, `%maildir` and
+    /// `%footer` start each section, and anything before the first of them
+    /// is part of the maildir section, so a file without them is all entry.
+    pub fn parse(contents: &str) -> Template {
+        let mut template = Template::entry("");
+
+        let mut section = &mut template.entry;
+        for line in contents.split_inclusive('\n') {
+            match line.trim_end_matches(['\n', '\r']) {
+                "%header"  => section = &mut template.header,
+                "%maildir" => section = &mut template.entry,
+                "%footer"  => section = &mut template.footer,
+                _          => section.push_str(line),
+            }
+        }
+
+        template
+    }
+}
+
+/// Options controlling how each maildir is written out. The template should
+/// have been checked with `check_template`.
+pub struct OutputFormat {
+    pub template: String,
+    pub escape: Escape,
+    pub maildirpp: bool,
+    pub absolute: bool,
+    pub named: bool,
+    pub labels: HashMap<PathBuf, String>,
+    pub human: bool,
+    pub subdir: Option<String>,
+    pub renames: HashMap<PathBuf, String>,
+    pub unix_separators: bool,
+    pub strip_suffixes: Vec<String>,
+    pub path_separator: Option<String>,
+}
+
+/// Turns a Maildir++ folder name like `.Lists.rust` into `Lists/rust`.
+/// Only components starting with a dot are folders, anything else is left as
+/// it is. A dot escaped with a backslash is a literal dot in the folder name.
+pub fn maildirpp_name(component: &str) -> String {
+    if !component.starts_with('.') {
+        return component.to_owned();
+    }
+
+    let mut name = String::with_capacity(component.len());
+    let mut chars = component[1..].chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                match chars.next() {
+                    Some('.') => name.push('.'),
+                    Some(c)   => {
+                        name.push('\\');
+                        name.push(c);
+                    },
+                    None      => name.push('\\'),
+                }
+            },
+            '.'  => name.push('/'),
+            c    => name.push(c),
+        }
+    }
+
+    name
+}
+
+/// Gets the path of a maildir as it should be displayed.
+/// Absolute paths are always shown as they are, since a Maildir++ name only
+/// makes sense relative to the base. The first of `strip_suffixes` that the
+/// path ends with is removed.
+pub fn display_path(path: &Path, format: &OutputFormat) -> String {
+    let display = if !format.maildirpp || path.is_absolute() {
+        path.display().to_string()
+    } else {
+        path.components()
+            .map(|c| maildirpp_name(&c.as_os_str().to_string_lossy()))
+            .collect::<Vec<String>>()
+            .join("/")
+    };
+
+    let stripped = format.strip_suffixes
+        .iter()
+        .filter(|s| !s.is_empty())
+        .find_map(|s| display.strip_suffix(s.as_str()))
+        .map(str::to_owned);
+
+    stripped.unwrap_or(display)
+}
+
+/// Formats a size in bytes using binary units, like `1.5 MiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Gets the label a maildir is shown with in neomutt's sidebar.
+/// This is the label it was given in `labels`, or the last component of its
+/// displayed path.
+pub fn label(maildir: &Maildir, format: &OutputFormat) -> String {
+    if let Some(label) = format.labels.get(&maildir.path) {
+        return label.to_owned();
+    }
+
+    let path = display_path(&maildir.path, format);
+
+    match path.rsplit('/').next() {
+        Some(l) if !l.is_empty() => l.to_owned(),
+        _                        => path,
+    }
+}
+
+// Gets the path a maildir is shown with before any subdir or escaping, which
+// is the name it was given in `renames` if it has one.
+fn shown_path(maildir: &Maildir, format: &OutputFormat) -> String {
+    if let Some(name) = format.renames.get(&maildir.path) {
+        return name.to_owned();
+    }
+
+    let path = if format.absolute {
+        &maildir.full_path
+    } else {
+        &maildir.path
+    };
+
+    // Named entries keep the real path for mutt, only the label is prettied.
+    if format.named {
+        path.display().to_string()
+    } else {
+        display_path(path, format)
+    }
+}
+
+/// Finds the maildirs that would be shown with the same path, like
+/// `.Work.Inbox` and `Work/Inbox` with `maildirpp`. Each group of them is
+/// returned with the path they share, in the order they're listed.
+pub fn duplicate_names<'a>(maildirs: &'a [Maildir],
+                           format: &OutputFormat)
+                           -> Vec<(String, Vec<&'a Maildir>)> {
+    shared(maildirs, |m| shown_path(m, format))
+}
+
+/// Finds the maildirs that would be shown with the same label when `named`,
+/// like `.Lists.rust` and `Lists/rust` with `maildirpp`. Each group of them
+/// is returned with the label they share, in the order they're listed.
+pub fn duplicate_labels<'a>(maildirs: &'a [Maildir],
+                            format: &OutputFormat)
+                            -> Vec<(String, Vec<&'a Maildir>)> {
+    shared(maildirs, |m| label(m, format))
+}
+
+// Groups the maildirs that share a `key`, leaving out those that don't.
+fn shared<F>(maildirs: &[Maildir],
+             key: F)
+             -> Vec<(String, Vec<&Maildir>)>
+    where F: Fn(&Maildir) -> String
+{
+    let mut groups: Vec<(String, Vec<&Maildir>)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for maildir in maildirs {
+        let name = key(maildir);
+
+        match positions.get(&name) {
+            Some(&i) => groups[i].1.push(maildir),
+            None     => {
+                positions.insert(name.clone(), groups.len());
+                groups.push((name, vec![maildir]));
+            },
+        }
+    }
+
+    groups.retain(|g| g.1.len() > 1);
+    groups
+}
+
+// Fills in a template, getting the value of each placeholder from `value`.
+// {{ and }} are literal braces.
+fn fill<F>(template: &str, value: F) -> Result<String, Box<dyn Error>>
+    where F: Fn(&str) -> Option<String>
+{
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                output.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                output.push('}');
+            },
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        None      => {
+                            return Err("Unclosed { in the format, use {{ for \
+                                        a literal brace".into());
+                        },
+                        Some('}') => break,
+                        Some(c)   => name.push(c),
+                    }
+                }
+
+                match value(&name) {
+                    None    => {
+                        let message = format!("Unknown placeholder {{{}}} in \
+                                               the format",
+                                              name);
+                        return Err(message.into());
+                    },
+                    Some(v) => output.push_str(&v),
+                }
+            },
+            '}' => return Err("Unmatched } in the format, use }} for a \
+                               literal brace".into()),
+            c   => output.push(c),
+        }
+    }
+
+    Ok(output)
+}
+
+/// Checks that a template only has known placeholders: {} or {path} for the
+/// path, {basename} for its last folder, and {count}, {unread}, {size},
+/// {flagged} and {replied}. Braces are written as {{ and }}.
+pub fn check_template(template: &str) -> Result<(), Box<dyn Error>> {
+    fill(template, |name| {
+        if name.is_empty() || PLACEHOLDERS.contains(&name) {
+            Some(String::new())
+        } else {
+            None
+        }
+    })
+    .map(|_| ())
+}
+
+/// Formats a maildir for output by substituting its path for each `{}` or
+/// `{path}` in the template, its last folder for `{basename}`, its message
+/// counts for `{count}`, `{unread}`, `{flagged}` and `{replied}` and its size
+/// for `{size}` if they were counted. Counts that weren't taken are left as
+/// they are. Quotes in the path and label are escaped according to `escape`.
+/// With `absolute`, the full path to the maildir is used instead of the base
+/// relative one. With `named`, the entry is preceded by the maildir's quoted
+/// label. Any `subdir` is appended to the path, and a maildir in `renames` is
+/// shown with its new name. With `unix_separators`, the platform's path
+/// separator is swapped for a forward slash, or for `path_separator` in
+/// relative paths if one is given.
+pub fn format_maildir(maildir: &Maildir, format: &OutputFormat) -> String {
+    let mut path = shown_path(maildir, format);
+
+    let basename = path
+        .rsplit(['/', path::MAIN_SEPARATOR])
+        .next()
+        .unwrap_or("")
+        .to_owned();
+
+    // mutt only understands forward slashes, but other tools may want
+    // something else between folders.
+    match format.path_separator {
+        Some(ref separator) if !format.absolute => {
+            path = path.replace(path::MAIN_SEPARATOR, separator);
+        },
+        _                                       => {
+            if format.unix_separators && path::MAIN_SEPARATOR != '/' {
+                path = path.replace(path::MAIN_SEPARATOR, "/");
+            }
+        },
+    }
+
+    // Tools that index message directories can be given `cur` itself.
+    if let Some(ref subdir) = format.subdir {
+        path = format!("{}/{}", path, subdir);
+    }
+
+    let path = format.escape.apply(&path);
+    let basename = format.escape.apply(&basename);
+
+    let size = maildir.size.map(|size| {
+        if format.human {
+            human_size(size)
+        } else {
+            size.to_string()
+        }
+    });
+
+    let counted = |name: &str, value: Option<String>| {
+        Some(value.unwrap_or_else(|| format!("{{{}}}", name)))
+    };
+
+    // A bad template was caught when it was checked.
+    let output = fill(&format.template, |name| {
+        match name {
+            "" | "path" => Some(path.clone()),
+            "basename"  => Some(basename.clone()),
+            "count"     => counted(name, maildir.count.map(|c| c.to_string())),
+            "unread"    => counted(name, maildir.unread.map(|u| u.to_string())),
+            "size"      => counted(name, size.clone()),
+            "flagged"   => {
+                counted(name, maildir.flagged.map(|f| f.to_string()))
+            },
+            "replied"   => {
+                counted(name, maildir.replied.map(|r| r.to_string()))
+            },
+            _           => None,
+        }
+    })
+    .unwrap_or_default();
+
+    if format.named {
+        let label = format.escape.apply(&label(maildir, format));
+        format!("'{}' {}", label, output)
+    } else {
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fills `template` with the name of each placeholder in capitals.
+    fn shout(template: &str) -> Result<String, Box<dyn Error>> {
+        fill(template, |name| {
+            if name == "unknown" {
+                None
+            } else {
+                Some(name.to_uppercase())
+            }
+        })
+    }
+
+    // Makes a maildir at `path` in the base, with nothing counted.
+    fn maildir(path: &str) -> Maildir {
+        Maildir {
+            path: PathBuf::from(path),
+            full_path: PathBuf::from("/mail").join(path),
+            initial: false,
+            count: None,
+            unread: None,
+            size: None,
+            flagged: None,
+            replied: None,
+            mbox: false,
+        }
+    }
+
+    #[test]
+    fn named_maildirs_with_the_same_label() {
+        let maildirs = [
+            maildir(".Lists.rust"),
+            maildir("Lists/rust"),
+            maildir("Lists/go"),
+        ];
+        let format = OutputFormat {
+            template: String::from("{}"),
+            escape: Escape::None,
+            maildirpp: true,
+            absolute: false,
+            named: true,
+            labels: HashMap::new(),
+            human: false,
+            subdir: None,
+            renames: HashMap::new(),
+            unix_separators: false,
+            strip_suffixes: vec![],
+            path_separator: None,
+        };
+
+        assert!(duplicate_names(&maildirs, &format).is_empty());
+
+        let labels = duplicate_labels(&maildirs, &format);
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[0].0, "rust");
+        assert_eq!(labels[0].1.len(), 2);
+    }
+
+    #[test]
+    fn placeholders_are_filled() {
+        assert_eq!(shout("+{path} ({count})").unwrap(), "+PATH (COUNT)");
+        assert_eq!(shout("{}").unwrap(), "");
+        assert_eq!(shout("no placeholders").unwrap(), "no placeholders");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(shout("{{path}}").unwrap(), "{path}");
+        assert_eq!(shout("{{{path}}}").unwrap(), "{PATH}");
+        assert_eq!(shout("}}{{").unwrap(), "}{");
+    }
+
+    #[test]
+    fn unknown_placeholders() {
+        assert!(shout("{unknown}").is_err());
+        assert!(check_template("{path} {basename} {count}").is_ok());
+        assert!(check_template("{}").is_ok());
+        assert!(check_template("{nope}").is_err());
+    }
+
+    #[test]
+    fn unmatched_braces() {
+        assert!(shout("{path").is_err());
+        assert!(shout("path}").is_err());
+        assert!(check_template("{path} {").is_err());
+        assert!(check_template("} {path}").is_err());
+    }
+}
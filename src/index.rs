@@ -0,0 +1,230 @@
+/*
+ * Incremental walking with an index of directory mtimes
+ */
+use cache::walk_key;
+use serde_json;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use {found_maildir, Base, Maildir, Options, WalkFilter};
+
+// What a directory held when it was last read. A directory's mtime changes
+// whenever an entry is added to, removed from or renamed in it, so while it
+// stays the same the entries can be used without reading it again.
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    mtime: SystemTime,
+    children: Vec<PathBuf>,
+}
+
+/// The directories seen by the last run, and those seen by this one so far.
+#[derive(Deserialize, Serialize)]
+pub struct Index {
+    key: String,
+    #[serde(skip)]
+    last: HashMap<PathBuf, Entry>,
+    dirs: HashMap<PathBuf, Entry>,
+}
+
+// The state of a walk through the index.
+struct Walk<'a> {
+    base: &'a Base,
+    options: &'a Options,
+    filter: WalkFilter,
+    maildirs: Vec<Maildir>,
+    skipped: usize,
+    // The directories being walked, for spotting symlink loops.
+    ancestors: Vec<PathBuf>,
+}
+
+/// Gets the index at `path` made by an earlier walk of `bases`. It's empty
+/// if there isn't one yet, or it was made for a different walk.
+pub fn load(path: &Path, bases: &[Base], options: &Options) -> Index {
+    let key = walk_key(bases, options);
+
+    let last = fs::read_to_string(path)
+        .ok()
+        .and_then(|c| serde_json::from_str::<Index>(&c).ok())
+        .filter(|i| i.key == key)
+        .map(|i| i.dirs);
+
+    if last.is_none() {
+        info!("No usable index in {}, walking everything", path.display());
+    }
+
+    Index {
+        key,
+        last: last.unwrap_or_default(),
+        dirs: HashMap::new(),
+    }
+}
+
+impl Index {
+    /// Finds the maildirs under a single base, along with the number of
+    /// entries skipped because of errors. Only directories that changed since
+    /// the last run are read, the rest are taken from the index.
+    pub fn walk(&mut self,
+                base: &Base,
+                options: &Options)
+                -> (Vec<Maildir>, usize) {
+        let mut walk = Walk {
+            base,
+            options,
+            filter: WalkFilter::new(options),
+            maildirs: Vec::new(),
+            skipped: 0,
+            ancestors: Vec::new(),
+        };
+
+        self.visit(&mut walk, &base.path, 0);
+
+        (walk.maildirs, walk.skipped)
+    }
+
+    // Visits an entry of the walk at `depth` below the base, then the
+    // entries inside it.
+    fn visit(&mut self, walk: &mut Walk, path: &Path, depth: usize) {
+        debug!("Visiting {}", path.display());
+
+        let options = walk.options;
+        if let Some(maildir) = found_maildir(walk.base, path, depth, options) {
+            walk.maildirs.push(maildir);
+        }
+
+        // Depths are given for the maildirs, their `cur` directories are one
+        // level further down.
+        let too_deep = options.max_depth.is_some_and(|d| depth > d);
+        let link = fs::symlink_metadata(path)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false);
+
+        if too_deep || !path.is_dir() ||
+           (link && !options.follow_symlinks) {
+            return;
+        }
+
+        let real_path = fs::canonicalize(path)
+            .unwrap_or_else(|_| path.to_owned());
+        let ancestor = walk.ancestors.iter().find(|&a| *a == real_path);
+        if let Some(ancestor) = ancestor {
+            warn!("Symlink loop at {} back to {}",
+                  path.display(),
+                  ancestor.display());
+            return;
+        }
+
+        let children = match self.children(walk, path, depth) {
+            Err(e) => {
+                info!("Skipped: {}", e);
+                walk.skipped += 1;
+                return;
+            },
+            Ok(c)  => c,
+        };
+
+        walk.ancestors.push(real_path);
+        for child in &children {
+            self.visit(walk, child, depth + 1);
+        }
+        walk.ancestors.pop();
+    }
+
+    // Gets the entries the walk descends into from a directory, reading it
+    // only if it changed since the last run. Whether an entry is walked can
+    // change without the directory changing, like a hidden folder becoming a
+    // maildir, so the index holds every entry that might be and they're
+    // filtered each time.
+    fn children(&mut self,
+                walk: &Walk,
+                path: &Path,
+                depth: usize)
+                -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let describe = |e| format!("{}: {}", path.display(), e);
+        let mtime = fs::metadata(path)
+            .and_then(|m| m.modified())
+            .map_err(describe)?;
+
+        let children = match self.last.remove(path) {
+            Some(entry) if entry.mtime == mtime => {
+                debug!("Unchanged {}", path.display());
+                entry.children
+            },
+            _                                   => {
+                debug!("Reading {}", path.display());
+                let options = walk.options;
+                let cur = options.cur_name();
+                let in_maildir = path.file_name().is_some_and(|name| {
+                    name == cur || name == "new" || name == "tmp"
+                });
+                let mut children = Vec::new();
+
+                for entry in fs::read_dir(path).map_err(describe)? {
+                    let child = entry.map_err(describe)?.path();
+
+                    // Files are only walked when looking for mboxes, and
+                    // messages never are, so they needn't fill up the index.
+                    let file = options.include_mbox && !in_maildir;
+
+                    if child.is_dir() || (file && child.is_file()) {
+                        children.push(child);
+                    }
+                }
+
+                children.sort();
+                children
+            },
+        };
+
+        let walkable = children
+            .iter()
+            .filter(|c| walk.filter.is_walkable(c, depth + 1))
+            .cloned()
+            .collect();
+
+        // Paths that aren't valid UTF-8 can't be written to the index. The
+        // maildirs in them are skipped when listing anyway, so they're left
+        // out, and such a directory is read on every run.
+        if path.to_str().is_some() {
+            let entry = Entry {
+                mtime,
+                children: children
+                    .into_iter()
+                    .filter(|c| c.to_str().is_some())
+                    .collect(),
+            };
+            self.dirs.insert(path.to_owned(), entry);
+        }
+
+        Ok(walkable)
+    }
+
+    /// Writes the directories seen by this run to the index at `path`.
+    /// Those only seen by the last run are gone, and are dropped.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let gone = self.last
+            .keys()
+            .filter(|d| !self.dirs.contains_key(*d))
+            .inspect(|d| debug!("Dropped {} from the index", d.display()))
+            .count();
+
+        if gone > 0 {
+            info!("{} indexed directories have gone", gone);
+        }
+
+        let written = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|c| fs::write(path, c).map_err(|e| e.to_string()));
+
+        match written {
+            Err(e) => {
+                let message = format!("Couldn't write index {}: {}",
+                                      path.display(),
+                                      e);
+                Err(message.into())
+            },
+            Ok(()) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,1888 @@
+/*
+ * mutt-maildirs
+ */
+extern crate dirs;
+extern crate glob;
+extern crate jwalk;
+#[macro_use]
+extern crate log;
+extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+extern crate walkdir;
+
+/// Log target for maildirs being left out of the list.
+pub const EXCLUDED_TARGET: &str = "mutt_maildirs::excluded";
+/// Log target for maildirs matching an initial value.
+pub const INITIAL_TARGET: &str = "mutt_maildirs::initial";
+/// Log target for the summary of what's listed.
+pub const SUMMARY_TARGET: &str = "mutt_maildirs::summary";
+
+// Logs a maildir being left out, under its own target so it can be told
+// apart from the rest.
+macro_rules! excluded {
+    ($($arg:tt)*) => (info!(target: EXCLUDED_TARGET, $($arg)*))
+}
+
+pub mod cache;
+pub mod config;
+pub mod format;
+pub mod index;
+pub mod muttrc;
+pub mod seen;
+
+use glob::{MatchOptions, Pattern};
+use jwalk::Parallelism;
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::{WalkDir, WalkDirIterator};
+
+const TILDE: &str = "~";
+const TILDE_SLASH: &str = "~/";
+const MUTTIGNORE: &str = ".muttignore";
+
+/// Options controlling how maildirs are found and filtered.
+#[derive(Default)]
+pub struct Options {
+    /// Drop maildirs that aren't initial unless they have mail in `new`.
+    pub only_unread: bool,
+    /// Only count a `cur` as a maildir with `new` and `tmp` beside it.
+    pub strict: bool,
+    /// Only find maildirs at most this many directories below the base.
+    pub max_depth: Option<usize>,
+    /// Only find maildirs at least this many directories below the base.
+    pub min_depth: Option<usize>,
+    /// Walk into symlinked directories.
+    pub follow_symlinks: bool,
+    /// Sort by name without regard to case.
+    pub ignore_case: bool,
+    /// Reverse the sort order of the maildirs that aren't initial.
+    pub reverse: bool,
+    /// Count the messages in each listed maildir.
+    pub count: bool,
+    /// Count the unread messages in each listed maildir.
+    pub unread: bool,
+    /// Drop maildirs that aren't initial if they have no messages.
+    pub exclude_empty: bool,
+    /// Skip hidden directories, other than Maildir++ folders.
+    pub ignore_hidden: bool,
+    /// How many threads walk each base. One or none walks it on the calling
+    /// thread.
+    pub jobs: usize,
+    /// Sort the initial maildirs like the rest, instead of keeping their
+    /// given order.
+    pub sort_initial: bool,
+    /// Warn about initial and exclude values that match no maildir.
+    pub warn_unused: bool,
+    /// Compare paths one component at a time when ignoring case, as they
+    /// always are otherwise.
+    pub tree: bool,
+    /// Drop maildirs that aren't initial with fewer messages than this.
+    pub min_messages: Option<usize>,
+    /// Sort by the most recently modified message, newest first.
+    pub sort_mtime: bool,
+    /// Use the mtimes of `cur` and `new` instead of their messages' for
+    /// modification times.
+    pub dir_mtime: bool,
+    /// Add up the size of the messages in each listed maildir.
+    pub size: bool,
+    /// The name of the directory holding a maildir's messages. Empty means
+    /// `cur`.
+    pub cur_name: String,
+    /// A file to cache the walk in, reused while the bases are unchanged.
+    pub cache: Option<PathBuf>,
+    /// The most maildirs to list, initial ones included.
+    pub limit: Option<usize>,
+    /// Leave out maildirs matched by a `.muttignore` in a directory above
+    /// them.
+    pub muttignore: bool,
+    /// Sort by how many messages each maildir holds, most first.
+    pub sort_count: bool,
+    /// Let an initial value match any maildir with that last folder name.
+    pub match_basename: bool,
+    /// Find mbox files as well as maildirs.
+    pub include_mbox: bool,
+    /// Treat maildirs reached through symlinks as the one they point to
+    /// when dropping repeats.
+    pub resolve_links: bool,
+    /// Keys to sort by in turn, used instead of the other sort options.
+    pub sort_keys: Vec<SortKey>,
+    /// Treat initial values as glob patterns.
+    pub initial_glob: bool,
+    /// Walk into maildirs' `tmp` directories.
+    pub walk_tmp: bool,
+    /// Drop maildirs that aren't initial if not modified after this.
+    pub since: Option<SystemTime>,
+    /// Drop maildirs that aren't initial with more path components.
+    pub exclude_deeper: Option<usize>,
+    /// A file of the last run's unread mail. Maildirs that aren't initial
+    /// are dropped without new unread mail since, and it's updated.
+    pub seen_state: Option<PathBuf>,
+    /// Put the initial maildirs after the rest.
+    pub initial_last: bool,
+    /// Leave the maildirs that aren't initial in the order they were walked.
+    pub no_sort: bool,
+    /// A file indexing the walked directories, so only changed ones are
+    /// read.
+    pub index: Option<PathBuf>,
+    /// Count the flagged and replied to messages in each listed maildir.
+    pub flags: bool,
+}
+
+impl Options {
+    // Gets the name of the directory holding a maildir's messages, `cur`
+    // unless another was given.
+    pub(crate) fn cur_name(&self) -> &str {
+        if self.cur_name.is_empty() {
+            "cur"
+        } else {
+            &self.cur_name
+        }
+    }
+}
+
+/// A base directory to look for maildirs under, with the label used to keep
+/// its maildirs distinct from those of other bases.
+pub struct Base {
+    pub path: PathBuf,
+    pub label: Option<PathBuf>,
+    pub relative_to: Option<PathBuf>,
+}
+
+impl Base {
+    /// Gets the directory maildir paths are relative to, which is the base
+    /// itself unless another was given.
+    pub fn root(&self) -> &Path {
+        match self.relative_to {
+            None        => &self.path,
+            Some(ref r) => r,
+        }
+    }
+
+    /// Prefixes a base-relative maildir path with this base's label.
+    pub fn labelled(&self, path: &Path) -> PathBuf {
+        match self.label {
+            None        => path.to_owned(),
+            Some(ref l) => l.join(path),
+        }
+    }
+}
+
+/// A maildir that was found, with its path for display and matching, and its
+/// full path on disk. Counts are only present when they were asked for.
+#[derive(Clone, Serialize)]
+pub struct Maildir {
+    pub path: PathBuf,
+    #[serde(skip)]
+    pub full_path: PathBuf,
+    pub initial: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unread: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flagged: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replied: Option<usize>,
+    #[serde(skip)]
+    pub mbox: bool,
+}
+
+/// Patterns for maildirs to leave out of the list.
+/// When there are any `includes`, maildirs that match none of them are left
+/// out too, unless they're initial.
+#[derive(Default)]
+pub struct Excludes {
+    pub patterns: Vec<Pattern>,
+    pub regexes: Vec<Regex>,
+    pub prefixes: Vec<PathBuf>,
+    pub includes: Vec<Pattern>,
+}
+
+/// Something maildirs can be sorted by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortField {
+    Name,
+    Count,
+    Unread,
+    Size,
+    Mtime,
+}
+
+/// A field to sort by, and which way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortKey {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+/// Parses a list of sort keys like `unread:desc,name:asc`. Each field can be
+/// name, count, unread, size or mtime, and is sorted ascending unless it's
+/// followed by `:desc`.
+pub fn parse_sort_keys(keys: &str) -> Result<Vec<SortKey>, Box<dyn Error>> {
+    keys.split(',')
+        .map(|key| {
+            let (field, direction) = match key.split_once(':') {
+                None         => (key, "asc"),
+                Some((f, d)) => (f, d),
+            };
+
+            let field = match field.trim() {
+                "name"   => SortField::Name,
+                "count"  => SortField::Count,
+                "unread" => SortField::Unread,
+                "size"   => SortField::Size,
+                "mtime"  => SortField::Mtime,
+                f        => {
+                    let message = format!("Unknown sort field {:?}, expected \
+                                           name, count, unread, size or \
+                                           mtime",
+                                          f);
+                    return Err(message.into());
+                },
+            };
+
+            let descending = match direction.trim() {
+                "asc"  => false,
+                "desc" => true,
+                d      => {
+                    let message = format!("Unknown sort direction {:?}, \
+                                           expected asc or desc",
+                                          d);
+                    return Err(message.into());
+                },
+            };
+
+            Ok(SortKey { field, descending })
+        })
+        .collect()
+}
+
+/// Expand $VAR and ${VAR} anywhere in the path.
+/// Unknown variables are left as they were, unless `error_unset` is set, in
+/// which case they're an error.
+pub fn expand_vars(path: &str,
+                   error_unset: bool)
+                   -> Result<String, Box<dyn Error>> {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        // Work out the variable name and how much of the input it used,
+        // including any braces.
+        let (name, used) = if after.starts_with('{') {
+            match after.find('}') {
+                None      => ("", 0),
+                Some(end) => (&after[1..end], end + 1),
+            }
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            (&after[..end], end)
+        };
+
+        // A lone $ or an unterminated ${ is kept literally.
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+
+        match env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_)    => {
+                if error_unset {
+                    return Err(format!("Unknown variable ${}", name).into());
+                }
+                expanded.push_str(&rest[start..start + 1 + used]);
+            },
+        }
+
+        rest = &after[used..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Crude ~ -> $HOME expansion, followed by environment variable expansion.
+pub fn expand_path(path: &str,
+                   error_unset: bool)
+                   -> Result<PathBuf, Box<dyn Error>> {
+    expand_path_in(path, dirs::home_dir().as_deref(), error_unset)
+}
+
+/// Expands a path like `expand_path`, with `home` as the home directory.
+/// Only a leading `~` on its own or followed by a slash is expanded, so
+/// `~foo` and a `~` later in the path are left alone. It's an error to use
+/// `~` without a home directory.
+pub fn expand_path_in(path: &str,
+                      home: Option<&Path>,
+                      error_unset: bool)
+                      -> Result<PathBuf, Box<dyn Error>> {
+    let path = &expand_vars(path, error_unset)?;
+    let mut cut_len = 0;
+
+    if path == TILDE {
+        cut_len = TILDE.len();
+    }
+
+    if path.starts_with(TILDE_SLASH) {
+        cut_len = TILDE_SLASH.len();
+    }
+
+    // We can stop here and just return a PathBuf from the path if we're not
+    // replacing anything.
+    if cut_len == 0 {
+        return Ok(normalize(Path::new(path)));
+    }
+
+    // Otherwise, continue and replace the ~
+    let home = match home {
+        None       => return Err("Could not get your home dir".into()),
+        Some(path) => path,
+    };
+
+    let pathtmp = &path[cut_len..];
+
+    Ok(normalize(&home.join(pathtmp)))
+}
+
+// Rebuilds a path from its components, dropping any trailing separator so
+// `~/Mail/` and `~/Mail` give the same base.
+fn normalize(path: &Path) -> PathBuf {
+    path.components().collect()
+}
+
+// Filter for entries being a directory.
+fn is_dir(path: &Path) -> bool {
+    path.is_dir()
+}
+
+// Filter for hidden entries that we can skip.
+// Maildir++ folders are hidden too, so a hidden directory that is itself a
+// maildir isn't skipped. The base is never skipped.
+fn is_skippable_hidden(path: &Path, depth: usize, cur: &str) -> bool {
+    let hidden = match path.file_name() {
+        None       => false,
+        Some(name) => name.to_string_lossy().starts_with('.'),
+    };
+
+    depth > 0 && hidden && !path.join(cur).is_dir()
+}
+
+// Filter for a maildir's `tmp` directory, which only holds messages being
+// delivered. A folder that happens to be called tmp has no `cur` beside it.
+fn is_maildir_tmp(path: &Path, cur: &str) -> bool {
+    path.file_name() == Some("tmp".as_ref()) &&
+    path.parent().is_some_and(|p| p.join(cur).is_dir())
+}
+
+// What the walk descends into, kept apart from the options so it can be
+// handed to the walker's threads.
+#[derive(Clone)]
+struct WalkFilter {
+    ignore_hidden: bool,
+    files: bool,
+    walk_tmp: bool,
+    cur: String,
+}
+
+impl WalkFilter {
+    fn new(options: &Options) -> WalkFilter {
+        WalkFilter {
+            ignore_hidden: options.ignore_hidden,
+            files: options.include_mbox,
+            walk_tmp: options.walk_tmp,
+            cur: options.cur_name().to_owned(),
+        }
+    }
+
+    // Filter for the directories the walk descends into, pruning hidden
+    // ones if asked and maildirs' `tmp` directories unless asked not to.
+    // Files are only walked when looking for mboxes.
+    fn is_walkable(&self, path: &Path, depth: usize) -> bool {
+        let cur = &self.cur;
+
+        (is_dir(path) || (self.files && path.is_file())) &&
+        !(self.ignore_hidden && is_skippable_hidden(path, depth, cur)) &&
+        !(!self.walk_tmp && depth > 0 && is_maildir_tmp(path, cur))
+    }
+}
+
+// Filter for files that look like mboxes, which start with a `From ` line.
+// Dotfiles and messages inside maildirs aren't mboxes.
+fn is_mbox(path: &Path, cur: &str) -> bool {
+    let hidden = match path.file_name() {
+        None       => true,
+        Some(name) => name.to_string_lossy().starts_with('.'),
+    };
+
+    let in_maildir = match path.parent().and_then(Path::file_name) {
+        None       => false,
+        Some(name) => name == cur || name == "new" || name == "tmp",
+    };
+
+    if hidden || in_maildir || !path.is_file() {
+        return false;
+    }
+
+    let mut start = [0; 5];
+    match File::open(path).and_then(|mut f| f.read_exact(&mut start)) {
+        Err(_) => false,
+        Ok(()) => &start == b"From ",
+    }
+}
+
+// Filter for entry == "cur", or whatever the message directory is called.
+fn is_cur(path: &Path, cur: &str) -> bool {
+    match path.file_name() {
+        None       => false,
+        Some(name) => name == cur,
+    }
+}
+
+// Filter for a `cur` entry whose parent is a complete maildir, containing
+// all of `cur`, `new` and `tmp`.
+fn is_maildir(path: &Path, cur: &str) -> bool {
+    match path.parent() {
+        None         => false,
+        Some(parent) => {
+            [cur, "new", "tmp"]
+                .iter()
+                .all(|d| parent.join(d).is_dir())
+        },
+    }
+}
+
+// Gets the messages in a maildir subdirectory such as `new`.
+// Messages are regular files that aren't dotfiles. A missing or unreadable
+// directory has no messages.
+fn messages(dir: &Path) -> Vec<fs::DirEntry> {
+    let entries = match fs::read_dir(dir) {
+        Err(_) => return vec![],
+        Ok(e)  => e,
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .collect()
+}
+
+// Counts the messages in a maildir subdirectory.
+fn count_messages(dir: &Path) -> usize {
+    messages(dir).len()
+}
+
+// Gets the flags from the info section of a message filename, the part after
+// `:2,`. Filenames without an info section have no flags.
+fn message_flags(name: &str) -> &str {
+    match name.rfind(":2,") {
+        None    => "",
+        Some(i) => &name[i + 3..],
+    }
+}
+
+// Counts the unread messages in the maildir at `path`. That's everything in
+// `new`, plus anything in `cur` without the Seen flag.
+fn count_unread(path: &Path, cur: &str) -> usize {
+    let unseen = messages(&path.join(cur))
+        .iter()
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|name| !message_flags(name).contains('S'))
+        .count();
+
+    count_messages(&path.join("new")) + unseen
+}
+
+// Gets the unread messages in the maildir at `path` by the unique part of
+// their filenames, which stays the same when a message moves from `new` to
+// `cur` and gets its flags.
+fn unread_names(path: &Path, cur: &str) -> HashSet<String> {
+    let unseen = messages(&path.join(cur))
+        .into_iter()
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|name| !message_flags(name).contains('S'));
+
+    messages(&path.join("new"))
+        .into_iter()
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .chain(unseen)
+        .map(|name| name.split(':').next().unwrap_or("").to_owned())
+        .collect()
+}
+
+// Counts the messages in the maildir at `path` that are flagged and that
+// have been replied to. Messages without an info section have neither flag.
+fn count_flags(path: &Path, cur: &str) -> (usize, usize) {
+    let names = messages(&path.join(cur))
+        .into_iter()
+        .chain(messages(&path.join("new")))
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<String>>();
+
+    let flagged = names
+        .iter()
+        .filter(|name| message_flags(name).contains('F'))
+        .count();
+    let replied = names
+        .iter()
+        .filter(|name| message_flags(name).contains('R'))
+        .count();
+
+    (flagged, replied)
+}
+
+// Counts all of the messages in the maildir at `path`, in both `cur` and
+// `new`.
+fn count_maildir(path: &Path, cur: &str) -> usize {
+    count_messages(&path.join(cur)) + count_messages(&path.join("new"))
+}
+
+// Adds up the sizes in bytes of the messages in `cur` and `new`.
+fn maildir_size(path: &Path, cur: &str) -> u64 {
+    messages(&path.join(cur))
+        .into_iter()
+        .chain(messages(&path.join("new")))
+        .filter_map(|e| e.metadata().ok())
+        .map(|m| m.len())
+        .sum()
+}
+
+// Checks if the maildir at `path` has anything waiting in `new`.
+fn has_unread(path: &Path) -> bool {
+    count_messages(&path.join("new")) > 0
+}
+
+// Gets the last time anything changed in the maildir at `path`, from the
+// newest message in `cur` and `new`. With `dir_only`, only the mtimes of those
+// directories are checked, which is cheaper but misses flag changes.
+fn last_modified(path: &Path,
+                 dir_only: bool,
+                 cur: &str)
+                 -> Option<SystemTime> {
+    let subdirs = [path.join(cur), path.join("new")];
+
+    if dir_only {
+        return subdirs
+            .iter()
+            .filter_map(|d| fs::metadata(d).and_then(|m| m.modified()).ok())
+            .max();
+    }
+
+    subdirs
+        .iter()
+        .flat_map(|d| messages(d))
+        .filter_map(|e| e.metadata().and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// Expands the `{a,b}` alternatives in a glob pattern into a pattern for
+/// each, so `{Trash,Spam}/*` gives `Trash/*` and `Spam/*`. Alternatives can
+/// be nested. Braces without a comma between them are left alone, as in the
+/// shell.
+pub fn expand_braces(pattern: &str) -> Vec<String> {
+    let mut depth = 0;
+    let mut open = None;
+    let mut commas = Vec::new();
+
+    for (i, c) in pattern.char_indices() {
+        match c {
+            '{' => {
+                if depth == 0 {
+                    open = Some(i);
+                    commas.clear();
+                }
+                depth += 1;
+            },
+            ',' if depth == 1 => commas.push(i),
+            '}' if depth > 0 => {
+                depth -= 1;
+
+                if depth > 0 {
+                    continue;
+                }
+
+                // Only the first group with alternatives is expanded here,
+                // the rest are expanded along with the results.
+                let start = match open {
+                    Some(start) if !commas.is_empty() => start,
+                    _                                 => continue,
+                };
+
+                let prefix = &pattern[..start];
+                let suffix = &pattern[i + 1..];
+                let mut bounds = vec![start];
+                bounds.extend(&commas);
+                bounds.push(i);
+
+                return bounds
+                    .windows(2)
+                    .flat_map(|w| {
+                        let alternative = &pattern[w[0] + 1..w[1]];
+                        let expanded = format!("{}{}{}",
+                                               prefix,
+                                               alternative,
+                                               suffix);
+                        expand_braces(&expanded)
+                    })
+                    .collect();
+            },
+            _   => {},
+        }
+    }
+
+    vec![pattern.to_owned()]
+}
+
+// Glob matching options for excludes, where `*` doesn't cross directory
+// boundaries but `**` does.
+fn glob_options() -> MatchOptions {
+    MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    }
+}
+
+// Filter excluded maildirs
+// Each exclude is either a glob pattern or a regex, matched against the
+// relative maildir path, or a prefix that excludes a maildir and everything
+// beneath it.
+fn is_excluded(entry: &Path, excluded: &Excludes) -> bool {
+    let options = glob_options();
+    let path = entry.to_string_lossy();
+
+    excluded.patterns.iter().any(|p| p.matches_path_with(entry, options)) ||
+    excluded.regexes.iter().any(|r| r.is_match(&path)) ||
+    excluded.prefixes.iter().any(|p| entry.starts_with(p))
+}
+
+// Reads the glob patterns from the .muttignore file in `dir`, skipping blank
+// lines and comments. A missing file has no patterns.
+fn read_muttignore(dir: &Path) -> Vec<Pattern> {
+    let path = dir.join(MUTTIGNORE);
+    let contents = match fs::read_to_string(&path) {
+        Err(_) => return vec![],
+        Ok(c)  => c,
+    };
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| {
+            match Pattern::new(l) {
+                Err(e) => {
+                    warn!("Invalid pattern {} in {}: {}",
+                          l,
+                          path.display(),
+                          e);
+                    None
+                },
+                Ok(p)  => Some(p),
+            }
+        })
+        .collect()
+}
+
+// Finds the .muttignore file that excludes a maildir, if any.
+// Any directory above the maildir, up to its base, can have one, with
+// patterns relative to that directory. Like .gitignore, a pattern matching a
+// directory excludes everything beneath it. Files are only read once.
+fn muttignored(maildir: &Maildir,
+               bases: &[Base],
+               ignores: &mut HashMap<PathBuf, Vec<Pattern>>)
+               -> Option<PathBuf> {
+    let base = bases.iter().find(|b| maildir.full_path.starts_with(&b.path))?;
+    let options = glob_options();
+
+    for dir in maildir.full_path.ancestors().skip(1) {
+        if !dir.starts_with(&base.path) {
+            break;
+        }
+
+        let patterns = ignores
+            .entry(dir.to_owned())
+            .or_insert_with(|| read_muttignore(dir));
+
+        if patterns.is_empty() {
+            continue;
+        }
+
+        let relative = maildir.full_path.strip_prefix(dir).ok()?;
+        let mut prefix = PathBuf::new();
+
+        for component in relative.components() {
+            prefix.push(component);
+
+            if patterns.iter().any(|p| p.matches_path_with(&prefix, options)) {
+                return Some(dir.join(MUTTIGNORE));
+            }
+        }
+    }
+
+    None
+}
+
+// Checks a maildir against the include patterns. Everything is included when
+// there aren't any.
+fn is_included(entry: &Path, excluded: &Excludes) -> bool {
+    let options = glob_options();
+
+    excluded.includes.is_empty() ||
+    excluded.includes.iter().any(|p| p.matches_path_with(entry, options))
+}
+
+// Finds the exclude patterns and regexes that don't match any of the walked
+// maildirs.
+fn unused_excludes(walked: &[Maildir], excluded: &Excludes) -> Vec<String> {
+    let options = glob_options();
+
+    let patterns = excluded.patterns
+        .iter()
+        .filter(|p| {
+            !walked.iter().any(|m| p.matches_path_with(&m.path, options))
+        })
+        .map(|p| p.as_str().to_owned());
+
+    let regexes = excluded.regexes
+        .iter()
+        .filter(|r| {
+            !walked.iter().any(|m| r.is_match(&m.path.to_string_lossy()))
+        })
+        .map(|r| r.as_str().to_owned());
+
+    let prefixes = excluded.prefixes
+        .iter()
+        .filter(|p| !walked.iter().any(|m| m.path.starts_with(p)))
+        .map(|p| p.display().to_string());
+
+    patterns.chain(regexes).chain(prefixes).collect()
+}
+
+// Checks if a maildir matches a single initial value. That's its whole path,
+// or when matching basenames just its last component too, so `Inbox` matches
+// `work/Inbox`. Initial values can also be glob patterns, matched in the same
+// way as excludes. An invalid pattern matches nothing.
+fn matches_initial(maildir: &Path, initial: &Path, options: &Options) -> bool {
+    let basename = maildir.file_name().map(Path::new);
+
+    if options.initial_glob {
+        let pattern = match Pattern::new(&initial.to_string_lossy()) {
+            Err(_) => return false,
+            Ok(p)  => p,
+        };
+
+        let matches = |path: &Path| {
+            pattern.matches_path_with(path, glob_options())
+        };
+
+        return matches(maildir) ||
+               (options.match_basename && basename.is_some_and(matches));
+    }
+
+    maildir == initial ||
+    (options.match_basename && basename == Some(initial))
+}
+
+// Checks if a maildir was listed as an initial maildir.
+fn is_initial(maildir: &Path, initial: &[PathBuf], options: &Options) -> bool {
+    initial.iter().any(|i| matches_initial(maildir, i, options))
+}
+
+fn maildir_path(base: &Path, path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    // Attempt to get the parent of the path we were given.
+    // If we're successful, we strip the base prefix from it.
+    let maildir = match path.parent() {
+        None    => {
+            return Err(format!("No parent directory for {:?}", path).into())
+        },
+        Some(x) => x.strip_prefix(base),
+    };
+
+    // If stripping the base prefix was successful, return the maildir path.
+    match maildir {
+        Err(e) => Err(e.into()),
+        Ok(m)  => Ok(m.to_owned()),
+    }
+}
+
+/// Expands an initial or exclude value in the same way as the bases.
+/// These values are relative to the bases, but if one expands to an absolute
+/// path under a base, that base is swapped for its label so it can be
+/// compared against the walked maildirs.
+pub fn base_relative(bases: &[Base],
+                     path: &str,
+                     error_unset: bool)
+                     -> Result<PathBuf, Box<dyn Error>> {
+    let mut path = expand_path(path, error_unset)?;
+
+    // Bases are canonicalized, so absolute paths must be too if they're to
+    // match.
+    if path.is_absolute() {
+        path = fs::canonicalize(&path).unwrap_or(path);
+    }
+
+    for base in bases {
+        if let Ok(relative) = path.strip_prefix(base.root()) {
+            return Ok(base.labelled(relative));
+        }
+    }
+
+    Ok(path)
+}
+
+// Splits a path into the components it's sorted by in tree order.
+fn sort_components(path: &Path, ignore_case: bool) -> Vec<String> {
+    path.components()
+        .map(|c| {
+            let c = c.as_os_str().to_string_lossy();
+
+            if ignore_case {
+                c.to_lowercase()
+            } else {
+                c.into_owned()
+            }
+        })
+        .collect()
+}
+
+// Compares two maildirs for sorting, by their raw paths unless case is being
+// ignored.
+// Paths compare each component separately, so a parent always comes directly
+// before its children: `Lists`, `Lists/rust`, then `Lists-old`. Ignoring case
+// compares whole strings instead, which puts `Lists-old` before `Lists/rust`,
+// unless it's in tree order.
+fn compare_names(a: &Path, b: &Path, options: &Options) -> Ordering {
+    if options.tree {
+        let a = sort_components(a, options.ignore_case);
+        let b = sort_components(b, options.ignore_case);
+        a.cmp(&b)
+    } else if options.ignore_case {
+        let a = a.to_string_lossy().to_lowercase();
+        let b = b.to_string_lossy().to_lowercase();
+        a.cmp(&b)
+    } else {
+        a.cmp(b)
+    }
+}
+
+// Compares two maildirs for sorting like `compare_names`, backwards if we're
+// reversing.
+fn compare_maildirs(a: &Path, b: &Path, options: &Options) -> Ordering {
+    let order = compare_names(a, b, options);
+
+    if options.reverse {
+        order.reverse()
+    } else {
+        order
+    }
+}
+
+// Sorts maildirs according to the options.
+// Sorting by mtime puts the most recently modified first, and sorting by
+// count puts the fullest first. Sorting by count also counts each maildir.
+fn sort_maildirs(maildirs: &mut Vec<Maildir>, options: &Options) {
+    let cur = options.cur_name();
+
+    if !options.sort_keys.is_empty() {
+        sort_by_keys(maildirs, options);
+    } else if options.sort_mtime {
+        sort_by_largest(maildirs, options, |m| {
+            last_modified(&m.full_path, options.dir_mtime, cur)
+        });
+    } else if options.sort_count {
+        for maildir in maildirs.iter_mut() {
+            maildir.count = Some(count_maildir(&maildir.full_path, cur));
+        }
+
+        sort_by_largest(maildirs, options, |m| m.count);
+    } else {
+        maildirs.sort_by(|a, b| compare_maildirs(&a.path, &b.path, options));
+    }
+}
+
+// Sorts maildirs by the key, largest first, falling back to the usual order
+// for ties. Each maildir's key is only worked out once.
+fn sort_by_largest<K, F>(maildirs: &mut Vec<Maildir>, options: &Options, key: F)
+    where K: Ord,
+          F: Fn(&Maildir) -> K
+{
+    let mut keyed = maildirs
+        .drain(..)
+        .map(|m| (key(&m), m))
+        .collect::<Vec<_>>();
+
+    keyed.sort_by(|a, b| {
+        let order = if options.reverse {
+            a.0.cmp(&b.0)
+        } else {
+            b.0.cmp(&a.0)
+        };
+
+        order.then_with(|| compare_maildirs(&a.1.path, &b.1.path, options))
+    });
+
+    maildirs.extend(keyed.into_iter().map(|(_, m)| m));
+}
+
+// Sorts maildirs by each of the sort keys in turn, falling back to the usual
+// order when they're all tied. Anything a key needs is counted up front,
+// with the counts kept on the maildirs.
+fn sort_by_keys(maildirs: &mut Vec<Maildir>, options: &Options) {
+    let cur = options.cur_name();
+    let needs = |field| options.sort_keys.iter().any(|k| k.field == field);
+
+    for maildir in maildirs.iter_mut() {
+        if needs(SortField::Count) {
+            maildir.count = Some(count_maildir(&maildir.full_path, cur));
+        }
+
+        if needs(SortField::Unread) {
+            maildir.unread = Some(count_unread(&maildir.full_path, cur));
+        }
+
+        if needs(SortField::Size) {
+            maildir.size = Some(maildir_size(&maildir.full_path, cur));
+        }
+    }
+
+    let mut keyed = maildirs
+        .drain(..)
+        .map(|m| {
+            let mtime = if needs(SortField::Mtime) {
+                last_modified(&m.full_path, options.dir_mtime, cur)
+            } else {
+                None
+            };
+
+            (mtime, m)
+        })
+        .collect::<Vec<_>>();
+
+    keyed.sort_by(|a, b| {
+        options.sort_keys
+            .iter()
+            .fold(Ordering::Equal, |order, key| {
+                order.then_with(|| {
+                    let order = match key.field {
+                        SortField::Name   => {
+                            compare_maildirs(&a.1.path, &b.1.path, options)
+                        },
+                        SortField::Count  => a.1.count.cmp(&b.1.count),
+                        SortField::Unread => a.1.unread.cmp(&b.1.unread),
+                        SortField::Size   => a.1.size.cmp(&b.1.size),
+                        SortField::Mtime  => a.0.cmp(&b.0),
+                    };
+
+                    if key.descending {
+                        order.reverse()
+                    } else {
+                        order
+                    }
+                })
+            })
+            .then_with(|| compare_maildirs(&a.1.path, &b.1.path, options))
+    });
+
+    maildirs.extend(keyed.into_iter().map(|(_, m)| m));
+}
+
+// Reports an entry the walk had to skip. Symlink loops are warned about,
+// anything else, like a directory we can't read, is only logged.
+fn walk_error(path: Option<&Path>,
+              ancestor: Option<&Path>,
+              error: &dyn Display) {
+    match (path, ancestor) {
+        (Some(path), Some(ancestor)) => {
+            warn!("Symlink loop at {} back to {}",
+                  path.display(),
+                  ancestor.display());
+        },
+        _                            => info!("Skipped: {}", error),
+    }
+}
+
+// Turns a walked directory into a maildir, if it's a 'cur' Maildir directory
+// with 'new' and 'tmp' alongside it when we're being strict.
+// An mbox is found too, if we're looking for them.
+fn found_maildir(base: &Base,
+                 path: &Path,
+                 depth: usize,
+                 options: &Options)
+                 -> Option<Maildir> {
+    let cur = options.cur_name();
+    let mbox = options.include_mbox && is_mbox(path, cur);
+
+    if !mbox &&
+       (!is_cur(path, cur) || (options.strict && !is_maildir(path, cur))) {
+        return None;
+    }
+
+    // The walk can't limit depth for both, since an mbox is at its own depth
+    // and a maildir's `cur` is one deeper. A `cur` that is the base itself
+    // belongs to a maildir above the base, so it's left out.
+    let depth = if mbox { depth } else { depth.checked_sub(1)? };
+    if options.min_depth.is_some_and(|d| depth < d) ||
+       options.max_depth.is_some_and(|d| depth > d) {
+        return None;
+    }
+
+    // Skip any maildir we can't work out the path of, including those outside
+    // the directory paths are relative to.
+    let root = base.root();
+    let path = if mbox {
+        path.strip_prefix(root).ok()?.to_owned()
+    } else {
+        maildir_path(root, path).ok()?
+    };
+
+    // A base that is itself a maildir has no relative path, so it's named
+    // after its label, or the base directory when there isn't one.
+    let (path, full_path) = if path.as_os_str().is_empty() {
+        let name = match base.label {
+            None        => PathBuf::from(root.file_name()?),
+            Some(ref l) => l.clone(),
+        };
+
+        (name, root.to_owned())
+    } else {
+        (base.labelled(&path), root.join(&path))
+    };
+
+    Some(Maildir {
+        path,
+        full_path,
+        initial: false,
+        count: None,
+        unread: None,
+        size: None,
+        flagged: None,
+        replied: None,
+        mbox,
+    })
+}
+
+// Gets the shallowest depth the walk needs to go to for maildirs at `depth`.
+// That's their `cur` directories, one level down, unless mboxes at `depth`
+// are wanted too.
+fn min_walk_depth(depth: usize, options: &Options) -> usize {
+    if options.include_mbox {
+        depth
+    } else {
+        depth + 1
+    }
+}
+
+// Finds the maildirs under a single base, along with the number of entries
+// skipped because of errors.
+fn walk_base(base: &Base, options: &Options) -> (Vec<Maildir>, usize) {
+    if options.jobs > 1 {
+        return walk_base_parallel(base, options);
+    }
+
+    // Filter the Maildir into what we're really after.
+    // .. get an interator
+    // .. grab the directories, pruning hidden ones if asked and any tmp.
+    // .. that we can access, reporting and counting any we can't.
+    // .. that are maildirs.
+    // .. finally collect the vector of Maildirs
+    // Depths are given for the maildirs, their `cur` directories are one
+    // level further down.
+    let filter = WalkFilter::new(options);
+    let mut walker = WalkDir::new(&base.path)
+        .follow_links(options.follow_symlinks);
+
+    if let Some(depth) = options.max_depth {
+        walker = walker.max_depth(depth + 1);
+    }
+
+    if let Some(depth) = options.min_depth {
+        walker = walker.min_depth(min_walk_depth(depth, options));
+    }
+
+    let mut skipped = 0;
+
+    let maildirs = walker
+        .into_iter()
+        .filter_entry(|e| filter.is_walkable(e.path(), e.depth()))
+        .filter_map(|e| {
+            if let Err(ref err) = e {
+                walk_error(err.path(), err.loop_ancestor(), err);
+                skipped += 1;
+            }
+            e.ok()
+        })
+        .inspect(|e| debug!("Visiting {}", e.path().display()))
+        .filter_map(|e| found_maildir(base, e.path(), e.depth(), options))
+        .collect::<Vec<Maildir>>();
+
+    (maildirs, skipped)
+}
+
+// Finds the maildirs under a single base, walking it with several threads.
+// Directories are read in sorted order, so the result doesn't depend on how
+// many threads there are.
+fn walk_base_parallel(base: &Base, options: &Options) -> (Vec<Maildir>, usize) {
+    let filter = WalkFilter::new(options);
+
+    let mut walker = jwalk::WalkDir::new(&base.path)
+        .skip_hidden(false)
+        .sort(true)
+        .follow_links(options.follow_symlinks)
+        .parallelism(Parallelism::RayonNewPool(options.jobs))
+        .process_read_dir(move |_, _, _, children| {
+            children.retain(|child| {
+                match *child {
+                    Err(_)    => true,
+                    Ok(ref e) => filter.is_walkable(&e.path(), e.depth),
+                }
+            });
+        });
+
+    if let Some(depth) = options.max_depth {
+        walker = walker.max_depth(depth + 1);
+    }
+
+    if let Some(depth) = options.min_depth {
+        walker = walker.min_depth(min_walk_depth(depth, options));
+    }
+
+    let mut skipped = 0;
+
+    let maildirs = walker
+        .into_iter()
+        .filter_map(|e| {
+            if let Err(ref err) = e {
+                walk_error(err.path(), err.loop_ancestor(), err);
+                skipped += 1;
+            }
+            e.ok()
+        })
+        .inspect(|e| debug!("Visiting {}", e.path().display()))
+        .filter_map(|e| found_maildir(base, &e.path(), e.depth, options))
+        .collect::<Vec<Maildir>>();
+
+    (maildirs, skipped)
+}
+
+// Checks the contents of a maildir that isn't initial against the options,
+// giving the reason it should be dropped from the list, if any.
+// The messages in an mbox aren't looked at, so it's never dropped.
+fn drop_reason(maildir: &Maildir, options: &Options) -> Option<&'static str> {
+    let cur = options.cur_name();
+
+    if maildir.mbox {
+        return None;
+    }
+
+    if options.only_unread && !has_unread(&maildir.full_path) {
+        return Some("no unread mail");
+    }
+
+    if options.exclude_empty || options.min_messages.is_some() {
+        let count = count_maildir(&maildir.full_path, cur);
+
+        if options.exclude_empty && count == 0 {
+            return Some("no messages");
+        }
+
+        if let Some(min) = options.min_messages {
+            if count < min {
+                return Some("too few messages");
+            }
+        }
+    }
+
+    if let Some(since) = options.since {
+        let dir_only = options.dir_mtime;
+        let modified = last_modified(&maildir.full_path, dir_only, cur);
+
+        if modified.is_none_or(|m| m <= since) {
+            return Some("not modified recently");
+        }
+    }
+
+    None
+}
+
+// Sums up what's being listed out of the `walked` maildirs found, with the
+// totals of any counts taken.
+fn summary(maildirs: &[Maildir], walked: usize, options: &Options) -> String {
+    let initial = maildirs.iter().filter(|m| m.initial).count();
+    let mut summary = format!("Listed {} maildirs, {} initial, {} excluded",
+                              maildirs.len(),
+                              initial,
+                              walked.saturating_sub(maildirs.len()));
+
+    if options.count {
+        let count = maildirs.iter().filter_map(|m| m.count).sum::<usize>();
+        summary.push_str(&format!(", {} messages", count));
+    }
+
+    if options.unread {
+        let unread = maildirs.iter().filter_map(|m| m.unread).sum::<usize>();
+        summary.push_str(&format!(", {} unread", unread));
+    }
+
+    if options.flags {
+        let flagged = maildirs.iter().filter_map(|m| m.flagged).sum::<usize>();
+        let replied = maildirs.iter().filter_map(|m| m.replied).sum::<usize>();
+        summary.push_str(&format!(", {} flagged, {} replied",
+                                  flagged,
+                                  replied));
+    }
+
+    summary
+}
+
+// Describes anything wrong with the directory at `path` as a maildir.
+// Directories without a `cur` or `new` aren't maildirs, so nothing is wrong
+// with them.
+fn maildir_problem(path: &Path, cur: &str) -> Option<String> {
+    let subdirs = [cur, "new", "tmp"];
+    let missing = subdirs
+        .iter()
+        .filter(|d| !path.join(d).is_dir())
+        .cloned()
+        .collect::<Vec<&str>>();
+
+    if missing.contains(&cur) && missing.contains(&"new") {
+        return None;
+    }
+
+    // By now that's either `cur` or `new` on its own.
+    if missing.len() == 2 {
+        let present = subdirs
+            .iter()
+            .find(|d| !missing.contains(d))
+            .unwrap_or(&cur);
+
+        return Some(format!("{} has a {} with nothing beside it",
+                            path.display(),
+                            present));
+    }
+
+    if !missing.is_empty() {
+        return Some(format!("{} is missing {}",
+                            path.display(),
+                            missing.join(" and ")));
+    }
+
+    if count_maildir(path, cur) == 0 {
+        return Some(format!("{} has no messages", path.display()));
+    }
+
+    None
+}
+
+/// Walks each of the already expanded `bases` looking for problems: maildirs
+/// missing one of their directories, `cur` or `new` directories on their own,
+/// directories that can't be read and maildirs without any messages. Each
+/// one found is described.
+pub fn check_maildirs(bases: &[Base], options: &Options) -> Vec<String> {
+    let filter = WalkFilter::new(options);
+    let cur = options.cur_name();
+    let mut problems = Vec::new();
+
+    for base in bases {
+        let walker = WalkDir::new(&base.path)
+            .follow_links(options.follow_symlinks)
+            .sort_by(|a, b| a.cmp(b))
+            .into_iter()
+            .filter_entry(|e| filter.is_walkable(e.path(), e.depth()));
+
+        for entry in walker {
+            let entry = match entry {
+                Err(e) => {
+                    problems.push(format!("Couldn't read {}", e));
+                    continue;
+                },
+                Ok(e)  => e,
+            };
+
+            if entry.file_type().is_dir() {
+                problems.extend(maildir_problem(entry.path(), cur));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Lists the maildirs found under each of the already expanded `bases`.
+///
+/// Maildirs matching `initial` come first, in the order they're given, and
+/// the rest are sorted according to `options`, or left in the order they
+/// were walked in if `no_sort` is set. Anything matching `excluded` is left
+/// out.
+///
+/// Maildirs with paths that aren't valid UTF-8 are left out with a warning.
+/// They can't be written in the output or matched against initial and
+/// exclude values without being mangled, and a mangled path would give mutt
+/// a mailbox that doesn't exist.
+pub fn list_maildirs(bases: &[Base],
+                     initial: &[PathBuf],
+                     excluded: &Excludes,
+                     options: &Options)
+                     -> Result<Vec<Maildir>, Box<dyn Error>> {
+    for base in bases {
+        // A missing base would otherwise just produce an empty list.
+        if !base.path.exists() {
+            let message = format!("Base {} doesn't exist", base.path.display());
+            return Err(message.into());
+        }
+
+        if !base.path.is_dir() {
+            let message = format!("Base {} isn't a directory",
+                                  base.path.display());
+            return Err(message.into());
+        }
+    }
+
+    let cached = match options.cache {
+        None        => None,
+        Some(ref c) => cache::load(c, bases, options),
+    };
+
+    let walked = match cached {
+        Some(walked) => {
+            info!("Using the cached walk");
+            walked
+        },
+        None         => {
+            let mut walked = Vec::new();
+            let mut skipped = 0;
+            let mut index = options.index
+                .as_ref()
+                .map(|i| index::load(i, bases, options));
+
+            for base in bases {
+                let (maildirs, errors) = match index {
+                    Some(ref mut index) => index.walk(base, options),
+                    None                => walk_base(base, options),
+                };
+
+                // An empty base is fine, but worth knowing about when it's a
+                // surprise.
+                if maildirs.is_empty() {
+                    info!("No maildirs found under {}", base.path.display());
+                }
+
+                walked.extend(maildirs);
+                skipped += errors;
+            }
+
+            if skipped > 0 {
+                info!("Skipped {} entries because of errors", skipped);
+            }
+
+            // Like the cache, an index that can't be written only costs the
+            // next run time.
+            if let (Some(ref i), Some(ref index)) = (&options.index, &index) {
+                if let Err(e) = index.save(i) {
+                    warn!("{}", e);
+                }
+            }
+
+            // A cache that can't be written only costs the next run time.
+            if let Some(ref c) = options.cache {
+                if let Err(e) = cache::save(c, bases, options, &walked) {
+                    warn!("{}", e);
+                }
+            }
+
+            walked
+        },
+    };
+
+    info!("Walked {} cur directories", walked.len());
+    let walked_count = walked.len();
+
+    if options.warn_unused {
+        for exclude in unused_excludes(&walked, excluded) {
+            warn!("Exclude {} didn't match any maildir", exclude);
+        }
+    }
+
+    // Remove our exclusions, noting each one we drop.
+    let mut ignores = HashMap::new();
+    let dirs = walked
+        .into_iter()
+        .filter(|e| {
+            let utf8 = e.path.to_str().is_some() &&
+                       e.full_path.to_str().is_some();
+            if !utf8 {
+                warn!("Skipped {}: not valid UTF-8", e.full_path.display());
+            }
+            utf8
+        })
+        .filter(|e| {
+            let excluded = is_excluded(&e.path, excluded);
+            if excluded {
+                excluded!("Excluded {}: listed in excludes",
+                          e.path.display());
+            }
+            !excluded
+        })
+        .filter(|e| {
+            if !options.muttignore {
+                return true;
+            }
+
+            match muttignored(e, bases, &mut ignores) {
+                None       => true,
+                Some(file) => {
+                    excluded!("Excluded {}: listed in {}",
+                              e.path.display(),
+                              file.display());
+                    false
+                },
+            }
+        })
+        .collect::<Vec<Maildir>>();
+
+    let mut found_initial = Vec::with_capacity(initial.len());
+    let mut maildirs = Vec::with_capacity(dirs.len());
+
+    // Unread messages are compared against the last run's, if asked, and
+    // every maildir's are recorded for the next.
+    let cur = options.cur_name();
+    let last_unread = options.seen_state.as_ref().map(|p| seen::load(p));
+    let mut unread_now = seen::Unread::new();
+
+    // Go over our maildirs, pushing them into the maildirs vector after
+    // stripping off the base prefix.
+    for mut maildir in dirs {
+        let new_unread = match last_unread {
+            Some(ref last) if !maildir.mbox => {
+                let names = unread_names(&maildir.full_path, cur);
+                let new = match last.get(&maildir.full_path) {
+                    None       => !names.is_empty(),
+                    Some(last) => !names.is_subset(last),
+                };
+
+                unread_now.insert(maildir.full_path.clone(), names);
+                new
+            },
+            _                               => true,
+        };
+
+        // Sort mailboxes into two vectors depending if they're an initial
+        // maildir or not.
+        if is_initial(&maildir.path, initial, options) {
+            info!(target: INITIAL_TARGET,
+                  "Matched initial {}",
+                  maildir.path.display());
+            maildir.initial = true;
+            found_initial.push(maildir);
+        } else if !is_included(&maildir.path, excluded) {
+            excluded!("Excluded {}: not included", maildir.path.display());
+        } else if options.exclude_deeper.is_some_and(|n| {
+            maildir.path.components().count() > n
+        }) {
+            excluded!("Excluded {}: too deep", maildir.path.display());
+        } else if let Some(reason) = drop_reason(&maildir, options) {
+            // Initial maildirs always appear, but the rest can be dropped
+            // depending on their contents.
+            excluded!("Excluded {}: {}", maildir.path.display(), reason);
+        } else if !new_unread {
+            excluded!("Excluded {}: no new unread mail",
+                      maildir.path.display());
+        } else {
+            debug!("Kept {}", maildir.path.display());
+            maildirs.push(maildir);
+        }
+    }
+
+    // Like the cache, a state file that can't be written only costs the next
+    // run.
+    if let Some(ref p) = options.seen_state {
+        if let Err(e) = seen::save(p, &unread_now) {
+            warn!("{}", e);
+        }
+    }
+
+    // At this point, `found_initial` tells us which initial dirs exist and
+    // are actual maildirs. However, the order is all wrong. We want our
+    // initial directories to be in the order they were specified on the
+    // command line.
+    // The `initial` vector is in that order.
+    // We generate another vector based on `initial` and `found_initial` that
+    // is in the correct order. An initial value matching several maildirs by
+    // basename has them in name order, even when reversing.
+    let mut initial_order = Vec::with_capacity(found_initial.len());
+    for maildir in initial {
+        let mut found = found_initial
+            .iter()
+            .filter(|x| matches_initial(&x.path, maildir, options))
+            .cloned()
+            .collect::<Vec<Maildir>>();
+
+        if found.is_empty() && options.warn_unused {
+            warn!("Initial {} didn't match any maildir", maildir.display());
+        }
+
+        found.sort_by(|a, b| compare_names(&a.path, &b.path, options));
+        initial_order.extend(found);
+    }
+
+    // Initial maildirs can be sorted like the rest if asked.
+    if options.sort_initial {
+        sort_maildirs(&mut initial_order, options);
+    }
+
+    // Sort maildirs that aren't the initial bunch, unless they're wanted in
+    // the order they were walked.
+    if !options.no_sort {
+        sort_maildirs(&mut maildirs, options);
+    }
+
+    // Allocate a new vector and add the initial order and maildirs to it.
+    // A maildir can turn up more than once, if it was given as initial twice
+    // or its base was given twice, so only the first of each is kept.
+    // Resolving symlinks also catches one maildir found under two names.
+    let mut all = Vec::with_capacity(initial_order.len() + maildirs.len());
+    let mut seen = HashSet::with_capacity(all.capacity());
+    for maildir in initial_order.into_iter().chain(maildirs) {
+        let key = if options.resolve_links {
+            fs::canonicalize(&maildir.full_path)
+                .unwrap_or_else(|_| maildir.full_path.clone())
+        } else {
+            maildir.full_path.clone()
+        };
+
+        if seen.insert(key) {
+            all.push(maildir);
+        } else {
+            excluded!("Excluded {}: listed already", maildir.path.display());
+        }
+    }
+
+    // Initial maildirs come first, so they're the last to go.
+    if let Some(limit) = options.limit {
+        all.truncate(limit);
+    }
+
+    // They can be moved to the end once the limit's been applied.
+    if options.initial_last {
+        let initial = all.iter().take_while(|m| m.initial).count();
+        all.rotate_left(initial);
+    }
+
+    // Counting is left until last so only maildirs we're listing are counted.
+    for maildir in &mut all {
+        // Sorting may have counted already.
+        if options.count && maildir.count.is_none() {
+            maildir.count = Some(count_maildir(&maildir.full_path, cur));
+        }
+
+        if options.unread && maildir.unread.is_none() {
+            maildir.unread = Some(count_unread(&maildir.full_path, cur));
+        }
+
+        if options.size && maildir.size.is_none() {
+            maildir.size = Some(maildir_size(&maildir.full_path, cur));
+        }
+
+        if options.flags && maildir.flagged.is_none() {
+            let (flagged, replied) = count_flags(&maildir.full_path, cur);
+            maildir.flagged = Some(flagged);
+            maildir.replied = Some(replied);
+        }
+    }
+
+    for (i, maildir) in all.iter().enumerate() {
+        debug!("Listing {} at {}", maildir.path.display(), i + 1);
+    }
+
+    info!(target: SUMMARY_TARGET, "{}", summary(&all, walked_count, options));
+
+    Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    // Makes an empty directory for a test under the system's temporary one.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("mutt-maildirs-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(&dir).unwrap()
+    }
+
+    // Makes a maildir at `path`, with all of its directories.
+    fn make_maildir(path: &Path) {
+        for dir in &["cur", "new", "tmp"] {
+            fs::create_dir_all(path.join(dir)).unwrap();
+        }
+    }
+
+    // Lists the maildirs under a single base with the default options.
+    fn list(base: &Path) -> Vec<Maildir> {
+        let base = Base {
+            path: base.to_owned(),
+            label: None,
+            relative_to: None,
+        };
+
+        list_maildirs(&[base], &[], &Excludes::default(), &Options::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn base_that_is_a_maildir() {
+        let dir = test_dir("base-maildir");
+        let base = dir.join("Inbox");
+        make_maildir(&base);
+
+        let maildirs = list(&base);
+
+        assert_eq!(maildirs.len(), 1);
+        assert_eq!(maildirs[0].path, PathBuf::from("Inbox"));
+        assert_eq!(maildirs[0].full_path, base);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn base_that_is_a_cur_directory() {
+        let dir = test_dir("base-cur");
+        make_maildir(&dir.join("INBOX"));
+
+        assert!(list(&dir.join("INBOX").join("cur")).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bases_with_the_same_names_are_both_listed() {
+        let dir = test_dir("same-names");
+        let bases = ["a", "b"]
+            .iter()
+            .map(|b| {
+                let path = dir.join(b).join("Mail");
+                make_maildir(&path.join("Inbox"));
+
+                Base {
+                    path,
+                    label: Some(PathBuf::from("Mail")),
+                    relative_to: None,
+                }
+            })
+            .collect::<Vec<Base>>();
+
+        let maildirs = list_maildirs(&bases,
+                                     &[],
+                                     &Excludes::default(),
+                                     &Options::default())
+            .unwrap();
+
+        assert_eq!(maildirs.len(), 2);
+        assert_ne!(maildirs[0].full_path, maildirs[1].full_path);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trailing_separator_is_dropped() {
+        let home = Path::new("/home/user");
+
+        assert_eq!(expand_path_in("~/Mail/", Some(home), false).unwrap(),
+                   expand_path_in("~/Mail", Some(home), false).unwrap());
+        assert_eq!(expand_path_in("/srv/mail/", Some(home), false).unwrap(),
+                   PathBuf::from("/srv/mail"));
+    }
+
+    #[test]
+    fn trailing_separator_lists_the_same() {
+        let dir = test_dir("trailing-separator");
+        make_maildir(&dir.join("INBOX"));
+        make_maildir(&dir.join("Lists").join("rust"));
+
+        let with = format!("{}/", dir.display());
+        let with = list(&expand_path_in(&with, None, false).unwrap());
+        let without = list(&dir);
+
+        let paths = |m: &[Maildir]| {
+            m.iter()
+                .map(|m| (m.path.clone(), m.full_path.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(&with), paths(&without));
+        assert_eq!(with.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tilde_expansion() {
+        let home = Some(Path::new("/home/user"));
+        let expand = |path| expand_path_in(path, home, false).unwrap();
+
+        assert_eq!(expand("~"), PathBuf::from("/home/user"));
+        assert_eq!(expand("~/"), PathBuf::from("/home/user"));
+        assert_eq!(expand("~/Mail"), PathBuf::from("/home/user/Mail"));
+        assert_eq!(expand("~foo"), PathBuf::from("~foo"));
+        assert_eq!(expand("Mail/~/x"), PathBuf::from("Mail/~/x"));
+    }
+
+    #[test]
+    fn tilde_without_home() {
+        assert!(expand_path_in("~", None, false).is_err());
+        assert!(expand_path_in("~/Mail", None, false).is_err());
+        assert_eq!(expand_path_in("~foo", None, false).unwrap(),
+                   PathBuf::from("~foo"));
+    }
+
+    #[test]
+    fn variables_are_expanded() {
+        env::set_var("MUTT_MAILDIRS_TEST_MAIL", "/srv/mail");
+        let expand = |path| expand_vars(path, true).unwrap();
+
+        assert_eq!(expand("$MUTT_MAILDIRS_TEST_MAIL/work"), "/srv/mail/work");
+        assert_eq!(expand("${MUTT_MAILDIRS_TEST_MAIL}work"), "/srv/mailwork");
+        assert_eq!(expand("a$MUTT_MAILDIRS_TEST_MAIL$MUTT_MAILDIRS_TEST_MAIL"),
+                   "a/srv/mail/srv/mail");
+    }
+
+    #[test]
+    fn lone_dollars_are_kept() {
+        let expand = |path| expand_vars(path, true).unwrap();
+
+        assert_eq!(expand("$"), "$");
+        assert_eq!(expand("cost$/x"), "cost$/x");
+        assert_eq!(expand("${}"), "${}");
+        assert_eq!(expand("${UNCLOSED"), "${UNCLOSED");
+    }
+
+    #[test]
+    fn unset_variables() {
+        env::remove_var("MUTT_MAILDIRS_TEST_UNSET");
+
+        assert_eq!(expand_vars("$MUTT_MAILDIRS_TEST_UNSET/x", false).unwrap(),
+                   "$MUTT_MAILDIRS_TEST_UNSET/x");
+        assert_eq!(expand_vars("${MUTT_MAILDIRS_TEST_UNSET}", false).unwrap(),
+                   "${MUTT_MAILDIRS_TEST_UNSET}");
+        assert!(expand_vars("$MUTT_MAILDIRS_TEST_UNSET", true).is_err());
+        assert!(expand_vars("${MUTT_MAILDIRS_TEST_UNSET}", true).is_err());
+    }
+
+    #[test]
+    fn sort_keys() {
+        let key = |field, descending| SortKey { field, descending };
+
+        assert_eq!(parse_sort_keys("name").unwrap(),
+                   [key(SortField::Name, false)]);
+        assert_eq!(parse_sort_keys("unread:desc,name:asc").unwrap(),
+                   [key(SortField::Unread, true), key(SortField::Name, false)]);
+        assert_eq!(parse_sort_keys(" count , size:desc,mtime").unwrap(),
+                   [key(SortField::Count, false),
+                    key(SortField::Size, true),
+                    key(SortField::Mtime, false)]);
+    }
+
+    #[test]
+    fn bad_sort_keys() {
+        assert!(parse_sort_keys("date").is_err());
+        assert!(parse_sort_keys("name:down").is_err());
+        assert!(parse_sort_keys("name,").is_err());
+        assert!(parse_sort_keys("").is_err());
+    }
+
+    #[test]
+    fn flags_of_messages() {
+        assert_eq!(message_flags("1.host:2,FS"), "FS");
+        assert_eq!(message_flags("1.host:2,"), "");
+        assert_eq!(message_flags("1.host"), "");
+        assert_eq!(message_flags("1.host:1,S"), "");
+    }
+
+    // Windows doesn't allow a : in a filename.
+    #[cfg(unix)]
+    #[test]
+    fn unread_messages() {
+        let dir = test_dir("unread");
+        make_maildir(&dir);
+        for message in &["new/1", "cur/2:2,S", "cur/3:2,FR", "cur/4"] {
+            fs::write(dir.join(message), "").unwrap();
+        }
+
+        // Only the message with the Seen flag has been read, even though the
+        // one without an info section is in `cur`.
+        assert_eq!(count_unread(&dir, "cur"), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn brace_alternatives() {
+        assert_eq!(expand_braces("{Trash,Spam}/*"), ["Trash/*", "Spam/*"]);
+        assert_eq!(expand_braces("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expand_braces("Lists/*"), ["Lists/*"]);
+    }
+
+    #[test]
+    fn nested_brace_alternatives() {
+        assert_eq!(expand_braces("{a,b{c,d}}"), ["a", "bc", "bd"]);
+        assert_eq!(expand_braces("{a{1,2},b}x"), ["a1x", "a2x", "bx"]);
+    }
+
+    #[test]
+    fn braces_without_alternatives() {
+        assert_eq!(expand_braces("{x}"), ["{x}"]);
+        assert_eq!(expand_braces("{x}{a,b}"), ["{x}a", "{x}b"]);
+    }
+
+    #[test]
+    fn unclosed_braces() {
+        assert_eq!(expand_braces("{a,b"), ["{a,b"]);
+        assert_eq!(expand_braces("a,b}"), ["a,b}"]);
+    }
+
+    #[test]
+    fn several_brace_groups() {
+        assert_eq!(expand_braces("{a,b}/{c,d}"), ["a/c", "a/d", "b/c", "b/d"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_are_skipped() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = test_dir("non-utf8");
+        make_maildir(&dir.join("INBOX"));
+        make_maildir(&dir.join(OsStr::from_bytes(b"bad\xff")));
+
+        let maildirs = list(&dir);
+
+        assert_eq!(maildirs.len(), 1);
+        assert_eq!(maildirs[0].path, PathBuf::from("INBOX"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,203 +3,2152 @@
  */
 #[macro_use]
 extern crate clap;
-extern crate walkdir;
+extern crate env_logger;
+extern crate glob;
+#[macro_use]
+extern crate log;
+extern crate mutt_maildirs;
+extern crate notify;
+extern crate regex;
+extern crate serde_json;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, ErrorKind, Shell};
+use env_logger::fmt::style::{AnsiColor, Style};
+use env_logger::WriteStyle;
+use glob::Pattern;
+use log::{Level, LevelFilter};
+use mutt_maildirs::{config, muttrc};
+use mutt_maildirs::format::{self, Escape, OutputFormat, Template};
+use mutt_maildirs::{base_relative, check_maildirs, expand_path, list_maildirs};
+use mutt_maildirs::{expand_braces, parse_sort_keys};
+use mutt_maildirs::{EXCLUDED_TARGET, INITIAL_TARGET, SUMMARY_TARGET};
+use mutt_maildirs::{Base, Excludes, Maildir, Options};
+use notify::{EventKind, RecursiveMode, Watcher};
+use regex::Regex;
+use std::collections::HashMap;
 use std::env;
-use std::path::{Path, PathBuf};
-use walkdir::{DirEntry, WalkDir, WalkDirIterator};
+use std::ffi::OsString;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+
+// Environment variable holding a base to use when none is given.
+const BASE_VAR: &str = "MUTT_MAILDIRS_BASE";
 
-const TILDE: &'static str = "~";
-const TILDE_SLASH: &'static str = "~/";
+// Gets the base from the environment, if it's set to something.
+fn env_base() -> Option<Vec<String>> {
+    match env::var(BASE_VAR) {
+        Ok(ref b) if !b.is_empty() => Some(vec![b.to_owned()]),
+        _                          => None,
+    }
+}
 
-// Crude ~ -> $HOME expansion.
-// Can possibly use String.replacen here in the future
-// Must be a better way than this.
-fn expand_path(path: &str) -> PathBuf {
-    let mut cut_len = 0;
+// Reads a single base line from stdin, as long as it isn't a terminal.
+fn stdin_base() -> io::Result<Vec<String>> {
+    let stdin = io::stdin();
 
-    if path.starts_with(TILDE) {
-        cut_len = TILDE.len();
+    if stdin.is_terminal() {
+        return Ok(vec![]);
     }
 
-    if path.starts_with(TILDE_SLASH) {
-        cut_len = TILDE_SLASH.len();
+    let mut line = String::new();
+    stdin.lock().read_line(&mut line)?;
+
+    let line = line.trim_end_matches(['\n', '\r']);
+
+    if line.is_empty() {
+        Ok(vec![])
+    } else {
+        Ok(vec![line.to_owned()])
     }
+}
+
+// Interprets the \n, \t, \f and \\ escapes in a separator given on the
+// command line, so separators can be given without shell quoting tricks.
+// Any other backslash is left as it is.
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let escaped = match (c, chars.peek()) {
+            ('\\', Some('n'))  => '\n',
+            ('\\', Some('t'))  => '\t',
+            ('\\', Some('f'))  => '\x0c',
+            ('\\', Some('\\')) => '\\',
+            (c, _)             => {
+                unescaped.push(c);
+                continue;
+            },
+        };
 
-    // We can stop here and just return a PathBuf from the path if we're not
-    // replacing anything.
-    if cut_len == 0 {
-        return PathBuf::from(path);
+        chars.next();
+        unescaped.push(escaped);
     }
 
-    // Otherwise, continue and replace the ~
-    let home = match env::home_dir() {
-        None       => panic!("Could not get your home dir."),
-        Some(path) => path,
+    unescaped
+}
+
+// Reads a list of maildirs from a file, one per line. Blank lines and lines
+// starting with # are ignored.
+fn read_list(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let file = match File::open(path) {
+        Err(e) => {
+            let message = format!("Couldn't open {}: {}", path.display(), e);
+            return Err(message.into());
+        },
+        Ok(f)  => f,
     };
 
-    let pathtmp = &path[cut_len..];
+    read_lines(BufReader::new(file))
+}
+
+// Reads a list of recently used maildirs, most recent first. Something
+// else keeps the file, so it not existing yet just means there are none.
+fn read_mru(path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    if !path.exists() {
+        info!("No recently used maildirs in {}", path.display());
+        return Ok(vec![]);
+    }
+
+    read_list(path)
+}
+
+// Reads the lines of a list, skipping blank lines and # comments.
+fn read_lines<R: BufRead>(reader: R) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut values = Vec::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        values.push(line.to_owned());
+    }
 
-    home.join(&pathtmp)
+    Ok(values)
 }
 
-// Filter for entries being a directory.
-fn is_dir(entry: &DirEntry) -> bool {
-    entry.path().is_dir()
+// Reads a file of `path = label` lines giving the labels of named mailboxes.
+// Blank lines and lines starting with # are ignored.
+fn read_label_map(path: &Path)
+                  -> Result<HashMap<PathBuf, String>, Box<dyn Error>> {
+    Ok(read_mapping(path)?.into_iter().collect())
 }
 
-// Filter for entry == "cur"
-fn is_cur(entry: &DirEntry) -> bool {
-    entry.file_name() == "cur"
+// Reads a file of `path = name` lines, keeping them in order. Blank lines and
+// lines starting with # are ignored.
+fn read_mapping(path: &Path) -> Result<Vec<(PathBuf, String)>, Box<dyn Error>> {
+    let mut mapping = Vec::new();
+
+    for line in read_list(path)? {
+        match line.split_once('=') {
+            None                => {
+                let message = format!("Invalid line in {}: {}",
+                                      path.display(),
+                                      line);
+                return Err(message.into());
+            },
+            Some((path, name))  => {
+                mapping.push((PathBuf::from(path.trim()),
+                              name.trim().to_owned()));
+            },
+        }
+    }
+
+    Ok(mapping)
 }
 
-// Filter excluded maildirs
-fn is_excluded(entry: &PathBuf, excluded: &Vec<PathBuf>) -> bool {
-    excluded.contains(entry)
+// Gets the values given for a repeatable argument, followed by those read
+// from the file given for `file_name`, if any. A file of - is stdin, so a
+// list can be piped in.
+fn list_values(matches: &ArgMatches,
+               name: &str,
+               file_name: &str,
+               error_unset: bool)
+               -> Result<Vec<String>, Box<dyn Error>> {
+    let mut values = match matches.values_of(name) {
+        None    => vec![],
+        Some(x) => x.map(String::from).collect::<Vec<String>>(),
+    };
+
+    match matches.value_of(file_name) {
+        None       => {},
+        Some("-")  => {
+            let stdin = io::stdin();
+            values.extend(read_lines(stdin.lock())?);
+        },
+        Some(file) => {
+            let file = expand_path(file, error_unset)?;
+            values.extend(read_list(&file)?);
+        },
+    }
+
+    Ok(values)
 }
 
-// Checks if a maildir was listed as an initial maildir.
-fn is_initial(maildir: &PathBuf, initial: &Vec<PathBuf>) -> bool {
-    initial.contains(maildir)
+// Gets the value of an optional numeric argument, exiting with clap's usual
+// error if it doesn't parse.
+fn optional_value(matches: &ArgMatches, name: &str) -> Option<usize> {
+    if matches.is_present(name) {
+        Some(value_t_or_exit!(matches, name, usize))
+    } else {
+        None
+    }
 }
 
-fn maildir_path(base: &Path, path: &Path) -> PathBuf {
-    // Attempt to get the parent of the path we were given.
-    // If we're successful, we strip the base prefix from it.
-    let maildir = match path.parent() {
-        None    => panic!("No parent directory for {:?}", path),
-        Some(x) => x.strip_prefix(&base),
+// Parses a duration like 90s, 15m, 2h, 3d or 1w.
+fn parse_duration(duration: &str) -> Result<Duration, Box<dyn Error>> {
+    let invalid = || format!("Invalid duration {:?}, expected a number \
+                              followed by s, m, h, d or w",
+                             duration);
+
+    let unit = duration.chars().last().map_or(0, char::len_utf8);
+    let (number, unit) = duration.split_at(duration.len() - unit);
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _   => return Err(invalid().into()),
     };
 
-    // If stripping the base prefix was successful, return the maildir path.
-    match maildir {
-        Err(e) => panic!("{}", e),
-        Ok(m)  => m.to_owned(),
+    match number.parse::<u64>().ok().and_then(|n| n.checked_mul(seconds)) {
+        None    => Err(invalid().into()),
+        Some(n) => Ok(Duration::from_secs(n)),
     }
 }
 
-fn list_maildirs(base: &str,
-                 initial: &Vec<PathBuf>,
-                 excluded: &Vec<PathBuf>)
-                 -> Vec<PathBuf> {
-    let base = expand_path(&base);
+// Works out the time maildirs must have been modified after, from either
+// --since or the mtime of --since-file.
+fn since(matches: &ArgMatches) -> Result<Option<SystemTime>, Box<dyn Error>> {
+    if let Some(duration) = matches.value_of("since") {
+        let duration = parse_duration(duration)?;
 
-    // Filter the Maildir into what we're really after.
-    // .. get an interator
-    // .. grab the directories
-    // .. that we can access
-    // .. and it's a 'cur' Maildir directory.
-    // .. get the maildir path.
-    // .. remove our exclusions.
-    // .. finally collect the vector of PathBufs
-    let dirs = WalkDir::new(&base)
-        .into_iter()
-        .filter_entry(is_dir)
-        .filter_map(Result::ok)
-        .filter(is_cur)
-        .map(|e| maildir_path(&base, e.path()))
-        .filter(|e| !is_excluded(e, excluded))
-        .collect::<Vec<PathBuf>>();
-
-    let mut found_initial = Vec::with_capacity(initial.len());
-    let mut maildirs = Vec::with_capacity(dirs.len());
-
-    // Go over our maildirs, pushing them into the maildirs vector after
-    // stripping off the base prefix.
-    for maildir in dirs {
-        // Sort mailboxes into two vectors depending if they're an initial
-        // maildir or not.
-        if is_initial(&maildir, &initial) {
-            found_initial.push(maildir.clone());
-        } else {
-            maildirs.push(maildir.clone());
-        }
+        return match SystemTime::now().checked_sub(duration) {
+            None       => Err("The --since duration is too long".into()),
+            Some(time) => Ok(Some(time)),
+        };
     }
 
-    // At this point, `found_initial` tells us which initial dirs exist and
-    // are actual maildirs. However, the order is all wrong. We want our
-    // initial directories to be in the order they were specified on the
-    // command line.
-    // The `initial` vector is in that order.
-    // We generate another vector based on `initial` and `found_initial` that
-    // is in the correct order.
-    let mut initial_order = Vec::with_capacity(found_initial.len());
-    for maildir in initial {
-        let pathbuf = PathBuf::from(maildir);
-        if found_initial.iter().any(|x| x == &pathbuf) {
-            initial_order.push(pathbuf.clone());
+    match matches.value_of("since-file") {
+        None       => Ok(None),
+        Some(file) => {
+            let file = expand_path(file, matches.is_present("error-unset"))?;
+
+            match fs::metadata(&file).and_then(|m| m.modified()) {
+                Err(e)    => {
+                    let message = format!("Couldn't get the mtime of {}: {}",
+                                          file.display(),
+                                          e);
+                    Err(message.into())
+                },
+                Ok(mtime) => Ok(Some(mtime)),
+            }
+        },
+    }
+}
+
+// Gets the colours a log message is shown in, when they're on. Warnings
+// stand out, exclusions are red, initial maildirs bold and the summary dim.
+fn log_style(record: &log::Record) -> Style {
+    match record.target() {
+        _ if record.level() <= Level::Warn => {
+            AnsiColor::Yellow.on_default().bold()
+        },
+        EXCLUDED_TARGET                    => AnsiColor::Red.on_default(),
+        INITIAL_TARGET                     => Style::new().bold(),
+        SUMMARY_TARGET                     => Style::new().dimmed(),
+        _                                  => Style::new(),
+    }
+}
+
+// Turns each `--set key=value` into the argument it stands for, so
+// `--set reverse=true` is `--reverse` and `--set limit=5` is `--limit=5`.
+// A value of false leaves a flag out. Keys are the long arguments, with
+// sort as a short name for sort-key, and underscores work as dashes.
+fn expand_sets(args: Vec<OsString>) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let mut expanded = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let set = match arg.to_str() {
+            Some("--set")                      => args.next(),
+            Some(a) if a.starts_with("--set=") => Some(a[6..].into()),
+            _                                  => None,
+        };
+
+        let set = match set {
+            None      => {
+                if arg == "--set" {
+                    return Err("--set needs a key=value".into());
+                }
+
+                expanded.push(arg);
+                continue;
+            },
+            Some(set) => set.to_string_lossy().into_owned(),
+        };
+
+        let (key, value) = match set.split_once('=') {
+            None         => {
+                let message = format!("Invalid --set {}, expected key=value",
+                                      set);
+                return Err(message.into());
+            },
+            Some((k, v)) => (k.trim().replace('_', "-"), v.trim()),
+        };
+
+        let key = if key == "sort" { String::from("sort-key") } else { key };
+        let flag = format!("--{}", key);
+
+        // Checking the argument alone tells an unknown one from one that
+        // just needs other arguments too.
+        let known = build_cli().get_matches_from_safe(vec!["", &flag]);
+        if let Err(ref e) = known {
+            if e.kind == ErrorKind::UnknownArgument || key == "set" {
+                return Err(format!("Unknown --set key {}", key).into());
+            }
+        }
+
+        match value {
+            "false" => {},
+            "true"  => expanded.push(flag.into()),
+            v       => expanded.push(format!("{}={}", flag, v).into()),
         }
     }
 
-    // Sort maildirs that aren't the initial bunch.
-    maildirs.sort();
+    Ok(expanded)
+}
+
+// Sets up logging. RUST_LOG picks what's logged, in env_logger's usual
+// format. Otherwise warnings are logged unless we're quiet, and --verbose
+// adds the info messages.
+fn init_logging(verbose: bool, quiet: bool, color: WriteStyle) {
+    let mut builder = env_logger::Builder::new();
+    builder.write_style(color);
+
+    match env::var("RUST_LOG") {
+        Ok(ref filter) if !filter.is_empty() => {
+            builder.parse_filters(filter);
+        },
+        _                                    => {
+            let level = if quiet {
+                LevelFilter::Off
+            } else if verbose {
+                LevelFilter::Info
+            } else {
+                LevelFilter::Warn
+            };
 
-    // Allocate a new vector and add the initial order and maildirs to it.
-    let mut all = Vec::with_capacity(initial_order.len() + maildirs.len());
-    all.extend(initial_order);
-    all.extend(maildirs);
-    all
+            builder
+                .filter_level(level)
+                .format(|buf, record| {
+                    let style = log_style(record);
+
+                    match record.level() {
+                        Level::Error | Level::Warn => {
+                            writeln!(buf,
+                                     "{}mutt-maildirs: {}{:#}",
+                                     style,
+                                     record.args(),
+                                     style)
+                        },
+                        _                          => {
+                            writeln!(buf,
+                                     "{}{}{:#}",
+                                     style,
+                                     record.args(),
+                                     style)
+                        },
+                    }
+                });
+        },
+    }
+
+    builder.init();
 }
 
-fn main() {
-    let matches = App::new("mutt-maildirs")
+// Builds the command line interface.
+fn build_cli() -> App<'static, 'static> {
+    App::new("mutt-maildirs")
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .after_help("EXIT STATUS:\n    \
+                     0    Maildirs were listed\n    \
+                     1    An error occurred\n    \
+                     2    An --exclude-regex was invalid\n    \
+                     3    No maildirs were found\n    \
+                     4    --check found problems")
         .arg(Arg::with_name("base")
              .short("b")
              .long("base")
              .value_name("MAILDIR")
-             .help("Base directory of the Maildir to sort")
+             .help("Base directory of the Maildir to sort. May be given more \
+                    than once. Defaults to $MUTT_MAILDIRS_BASE, the config \
+                    file, or a line read from stdin")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("label")
+             .long("label")
+             .value_name("NAME")
+             .help("Label prefixed to the maildirs of the base in the same \
+                    position. With several bases, unlabelled ones use their \
+                    directory name, unless --relative-to is given")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("account-file")
+             .long("account-file")
+             .value_name("PATH")
+             .help("File of `folder = Account Name` lines. The maildirs in \
+                    each folder are listed separately after a # Account Name \
+                    comment, in the order of the file, and any others are \
+                    listed last as Other")
              .takes_value(true)
-             .required(true))
+             .conflicts_with_all(&["print0", "null"]))
+        .arg(Arg::with_name("prepend-base")
+             .long("prepend-base")
+             .help("Label the maildirs of a single base with its directory \
+                    name too, so Inbox under ~/Mail/work is work/Inbox. \
+                    With --relative-to, it's only added when that is inside \
+                    the base, as paths relative to a directory above it \
+                    already hold the name"))
+        .arg(Arg::with_name("relative-to")
+             .long("relative-to")
+             .value_name("PATH")
+             .help("Directory maildir paths are relative to, instead of \
+                    their base. Set this to mutt's folder if it isn't the \
+                    base. Maildirs outside it are left out. Their paths \
+                    already tell the bases apart, so only a --label, or \
+                    --prepend-base for a directory inside the base, is added \
+                    to them")
+             .takes_value(true))
         .arg(Arg::with_name("initial")
              .short("i")
              .long("initial")
              .value_name("INITIAL")
-             .help("Maildirs to be sorted first")
+             .help("Maildirs to be sorted first, relative to the base and \
+                    including any label. Absolute paths under a base are also \
+                    accepted")
              .takes_value(true)
              .multiple(true))
         .arg(Arg::with_name("exclude")
              .short("e")
              .long("exclude")
              .value_name("EXCLUDE")
-             .help("Maildirs to exclude from list")
+             .help("Maildirs to exclude from list, as glob patterns \
+                    relative to the base and including any label. Absolute \
+                    paths under a base are also accepted. Patterns can use \
+                    ?, * within a folder name, ** across folders, classes \
+                    like [0-9] or [!a-z] and alternatives like \
+                    {Trash,Spam}, but nothing else from the shell")
+             .takes_value(true)
+             .multiple(true))
+        .arg(Arg::with_name("exclude-deeper-than")
+             .long("exclude-deeper-than")
+             .value_name("N")
+             .help("Exclude maildirs with more than N folders in their path, \
+                    including any label, unless they're initial. Unlike \
+                    --max-depth, the walk still goes deeper")
+             .takes_value(true))
+        .arg(Arg::with_name("exclude-prefix")
+             .long("exclude-prefix")
+             .value_name("MAILDIR")
+             .help("Maildirs to exclude from the list along with every \
+                    maildir beneath them")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("muttignore")
+             .long("muttignore")
+             .help("Exclude maildirs matching the glob patterns in a \
+                    .muttignore file in any directory above them, relative \
+                    to that directory"))
+        .arg(Arg::with_name("include")
+             .long("include")
+             .value_name("INCLUDE")
+             .help("Only list maildirs matching one of these glob patterns, \
+                    as well as the initial ones. Excludes still apply. \
+                    Patterns are the same as for --exclude")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("initial-match")
+             .long("initial-match")
+             .value_name("MODE")
+             .help("How initial maildirs are matched, by their whole path, \
+                    or also by their last path component so Inbox matches \
+                    work/Inbox")
+             .takes_value(true)
+             .possible_values(&["path", "basename"])
+             .default_value("path"))
+        .arg(Arg::with_name("initial-last")
+             .long("initial-last")
+             .help("Put the initial maildirs after the rest instead of \
+                    before, still in the order they're given. With --limit, \
+                    they're still the last to be left out"))
+        .arg(Arg::with_name("initial-glob")
+             .long("initial-glob")
+             .help("Treat initial maildirs as glob patterns like the \
+                    excludes, so -i 'Work/*' puts all of Work first. The \
+                    maildirs each pattern matches are sorted among \
+                    themselves"))
+        .arg(Arg::with_name("initial-file")
+             .long("initial-file")
+             .value_name("PATH")
+             .help("File listing maildirs to be sorted first, one per line, \
+                    after any given with --initial. - reads them from stdin")
+             .takes_value(true))
+        .arg(Arg::with_name("mru-file")
+             .long("mru-file")
+             .value_name("PATH")
+             .help("File listing recently used maildirs, most recent first, \
+                    to be sorted first in that order after any given with \
+                    --initial or --initial-file. Ones that are no longer \
+                    maildirs are skipped")
+             .takes_value(true))
+        .arg(Arg::with_name("exclude-file")
+             .long("exclude-file")
+             .value_name("PATH")
+             .help("File listing maildirs to exclude, one per line, after \
+                    any given with --exclude. - reads them from stdin")
+             .takes_value(true))
+        .arg(Arg::with_name("exclude-stdin")
+             .long("exclude-stdin")
+             .help("Read maildirs to exclude from stdin, the same as \
+                    --exclude-file -")
+             .conflicts_with("exclude-file"))
+        .arg(Arg::with_name("exclude-regex")
+             .long("exclude-regex")
+             .value_name("PATTERN")
+             .help("Maildirs to exclude from list, as regular expressions")
              .takes_value(true)
              .multiple(true))
         .arg(Arg::with_name("verbose")
              .short("v")
              .long("verbose")
-             .help("Set verbose mode"))
-        .get_matches();
+             .help("Set verbose mode. RUST_LOG=debug logs in more detail"))
+        .arg(Arg::with_name("quiet")
+             .short("q")
+             .long("quiet")
+             .help("Don't print warnings. Errors are still printed and the \
+                    exit status is unchanged")
+             .conflicts_with("verbose"))
+        .arg(Arg::with_name("warn-unused")
+             .long("warn-unused")
+             .help("Warn about initial and exclude values that don't match \
+                    any maildir. Implied by --verbose"))
+        .arg(Arg::with_name("error-unset")
+             .long("error-unset")
+             .help("Treat unknown environment variables in paths as errors"))
+        .arg(Arg::with_name("only-unread")
+             .long("only-unread")
+             .help("Only list maildirs with messages in their new directory"))
+        .arg(Arg::with_name("strict")
+             .long("strict")
+             .help("Only list maildirs containing all of cur, new and tmp"))
+        .arg(Arg::with_name("cur-name")
+             .long("cur-name")
+             .value_name("NAME")
+             .help("Name of the directory holding a maildir's read messages, \
+                    for stores that don't call it cur")
+             .takes_value(true)
+             .default_value("cur"))
+        .arg(Arg::with_name("walk-tmp")
+             .long("walk-tmp")
+             .help("Look for maildirs inside the tmp directories of other \
+                    maildirs, which are skipped otherwise"))
+        .arg(Arg::with_name("resolve-symlinks")
+             .long("resolve-symlinks")
+             .help("Only list the first of maildirs that are the same \
+                    directory, such as one reached through a symlink under \
+                    another name")
+             .takes_value(false))
+        .arg(Arg::with_name("include-mbox")
+             .long("include-mbox")
+             .help("List mbox files along with the maildirs. Files starting \
+                    with a From line are taken to be mboxes. Their messages \
+                    aren't counted, so they're never left out for having no \
+                    mail")
+             .takes_value(false))
+        .arg(Arg::with_name("cache")
+             .long("cache")
+             .value_name("PATH")
+             .help("Cache the walk in PATH, reusing it while the bases and \
+                    the directories directly inside them are unchanged. \
+                    Folders added deeper down aren't noticed until a top \
+                    level directory changes or the cache is removed")
+             .takes_value(true))
+        .arg(Arg::with_name("index")
+             .long("index")
+             .value_name("PATH")
+             .help("Keep an index of the walked directories and their mtimes \
+                    in PATH, and only read those that changed since the last \
+                    run, taking the rest from the index. Folders added or \
+                    removed anywhere are noticed. The walk uses a single \
+                    thread")
+             .takes_value(true)
+             .conflicts_with("cache"))
+        .arg(Arg::with_name("max-depth")
+             .long("max-depth")
+             .value_name("N")
+             .help("Only list maildirs at most N directories below the base")
+             .takes_value(true))
+        .arg(Arg::with_name("top-level-only")
+             .long("top-level-only")
+             .help("Only list maildirs directly inside the base, the same as \
+                    --min-depth 1 --max-depth 1")
+             .conflicts_with_all(&["max-depth", "min-depth"]))
+        .arg(Arg::with_name("min-depth")
+             .long("min-depth")
+             .value_name("N")
+             .help("Only list maildirs at least N directories below the base")
+             .takes_value(true))
+        .arg(Arg::with_name("follow-symlinks")
+             .long("follow-symlinks")
+             .help("Follow symbolic links while looking for maildirs"))
+        .arg(Arg::with_name("ignore-case")
+             .long("ignore-case")
+             .help("Sort maildirs without regard to case"))
+        .arg(Arg::with_name("reverse")
+             .long("reverse")
+             .help("Reverse the sort order of maildirs that aren't initial"))
+        .arg(Arg::with_name("exclude-empty")
+             .long("exclude-empty")
+             .help("Exclude maildirs with no messages, unless they're \
+                    initial"))
+        .arg(Arg::with_name("min-messages")
+             .long("min-messages")
+             .value_name("N")
+             .help("Exclude maildirs with fewer than N messages in cur and \
+                    new, unless they're initial. This reads every maildir, \
+                    so it's slower")
+             .takes_value(true))
+        .arg(Arg::with_name("ignore-hidden")
+             .long("ignore-hidden")
+             .help("Don't look for maildirs in hidden directories. Hidden \
+                    directories that are maildirs themselves, such as \
+                    Maildir++ folders like .Lists.rust, are still listed"))
+        .arg(Arg::with_name("jobs")
+             .long("jobs")
+             .value_name("N")
+             .help("Walk the bases using N threads")
+             .takes_value(true))
+        .arg(Arg::with_name("sort-initial")
+             .long("sort-initial")
+             .help("Sort initial maildirs like the rest, instead of keeping \
+                    the order they were given in"))
+        .arg(Arg::with_name("tree")
+             .long("tree")
+             .help("With --ignore-case, still sort by each path component \
+                    in turn, so parent folders come directly before their \
+                    children. Paths are always sorted this way otherwise, so \
+                    on its own this makes no difference"))
+        .arg(Arg::with_name("sort-mtime")
+             .long("sort-mtime")
+             .help("Sort maildirs that aren't initial by their most recently \
+                    modified message, newest first. This reads every \
+                    maildir, so it's slower"))
+        .arg(Arg::with_name("sort-count")
+             .long("sort-count")
+             .help("Sort maildirs that aren't initial by how many messages \
+                    they hold, most first. Like --count, this is slow on \
+                    large stores")
+             .conflicts_with("sort-mtime"))
+        .arg(Arg::with_name("since")
+             .long("since")
+             .value_name("DURATION")
+             .help("Only list maildirs that aren't initial if they were \
+                    modified within DURATION, like 90s, 15m, 2h, 3d or 1w. \
+                    Like --sort-mtime, this reads every maildir, unless \
+                    --dir-mtime is given")
+             .takes_value(true))
+        .arg(Arg::with_name("since-file")
+             .long("since-file")
+             .value_name("PATH")
+             .help("Only list maildirs that aren't initial if they were \
+                    modified after PATH was")
+             .takes_value(true)
+             .conflicts_with("since"))
+        .arg(Arg::with_name("since-seen")
+             .long("since-seen")
+             .value_name("PATH")
+             .help("Only list maildirs that aren't initial if they have \
+                    unread mail that wasn't there last time. The unread mail \
+                    is recorded in the state file at PATH for the next run")
+             .takes_value(true))
+        .arg(Arg::with_name("sort-key")
+             .long("sort-key")
+             .value_name("KEYS")
+             .help("Sort maildirs that aren't initial by a comma separated \
+                    list of field:direction keys, like unread:desc,name:asc. \
+                    The fields are name, count, unread, size and mtime, and \
+                    the direction is asc or desc, asc if it's left off")
+             .takes_value(true)
+             .conflicts_with_all(&["sort-mtime", "sort-count", "reverse"]))
+        .arg(Arg::with_name("no-sort")
+             .long("no-sort")
+             .help("Leave maildirs that aren't initial in the order they were \
+                    walked in. This is the order the filesystem gives, so \
+                    it isn't guaranteed to stay the same between runs, or \
+                    with a different --jobs")
+             .conflicts_with_all(&["sort-mtime",
+                                   "sort-count",
+                                   "sort-key",
+                                   "tree",
+                                   "reverse"]))
+        .arg(Arg::with_name("dir-mtime")
+             .long("dir-mtime")
+             .help("With --sort-mtime, --since or an mtime sort key, use the \
+                    mtimes of cur and new instead of their messages. Cheaper, \
+                    but misses flag changes"))
+        .arg(Arg::with_name("limit")
+             .long("limit")
+             .value_name("N")
+             .help("Only list the first N maildirs, initial ones included")
+             .takes_value(true))
+        .arg(Arg::with_name("mailboxes")
+             .long("mailboxes")
+             .help("Output a complete mutt mailboxes command"))
+        .arg(Arg::with_name("wrap")
+             .long("wrap")
+             .value_name("N")
+             .help("Split the mailboxes or named-mailboxes command into \
+                    several, each with at most N maildirs. Needs --mailboxes \
+                    or --named. 0 doesn't split. With --group-separator, \
+                    each group is split on its own")
+             .takes_value(true))
+        .arg(Arg::with_name("named")
+             .long("named")
+             .help("Output a complete neomutt named-mailboxes command, \
+                    labelling each maildir with the last component of its \
+                    displayed path")
+             .conflicts_with("mailboxes"))
+        .arg(Arg::with_name("label-map")
+             .long("label-map")
+             .value_name("PATH")
+             .help("File of path = label lines overriding the labels used by \
+                    --named")
+             .takes_value(true)
+             .requires("named"))
+        .arg(Arg::with_name("format")
+             .long("format")
+             .value_name("TEMPLATE")
+             .help("Template for each output entry. {} or {path} is replaced \
+                    with the maildir path, {basename} with its last folder, \
+                    {count} with its message count, {unread} with its unread \
+                    count, {size} with its size, and {flagged} and {replied} \
+                    with its flagged and replied counts. {{ and }} are \
+                    literal braces. Defaults to +'{}'")
+             .takes_value(true))
+        .arg(Arg::with_name("template-file")
+             .long("template-file")
+             .value_name("PATH")
+             .help("Template file for the whole output. Lines of %header, \
+                    %maildir and %footer start its sections. The maildir \
+                    section is filled in for each maildir like --format, \
+                    with its line breaks, and the header and footer are \
+                    written before and after as they are. A file without \
+                    sections is all maildir")
+             .takes_value(true)
+             .conflicts_with_all(&["format", "prefix", "suffix", "mailboxes",
+                                   "named", "print0", "null",
+                                   "account-file"]))
+        .arg(Arg::with_name("prefix")
+             .long("prefix")
+             .value_name("STR")
+             .help("Put STR before each quoted path instead of +, such as = \
+                    for mutt's folder shorthand")
+             .takes_value(true)
+             .conflicts_with("format"))
+        .arg(Arg::with_name("suffix")
+             .long("suffix")
+             .value_name("STR")
+             .help("Put STR after each quoted path")
+             .takes_value(true)
+             .conflicts_with("format"))
+        .arg(Arg::with_name("absolute")
+             .long("absolute")
+             .help("Output the full path to each maildir instead of the path \
+                    relative to its base, without the + prefix unless \
+                    --prefix or --format is given"))
+        .arg(Arg::with_name("with-cur")
+             .long("with-cur")
+             .help("Output the path to each maildir's cur directory instead \
+                    of the maildir itself"))
+        .arg(Arg::with_name("strip-suffix")
+             .long("strip-suffix")
+             .value_name("STR")
+             .help("Remove STR from the end of each displayed path. Given \
+                    more than once, the first that matches is removed")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("unix-separators")
+             .long("unix-separators")
+             .help("Output paths with forward slashes. Always on for \
+                    Windows, where paths use backslashes"))
+        .arg(Arg::with_name("escape")
+             .long("escape")
+             .value_name("MODE")
+             .help("How to escape single quotes in maildir paths and labels. \
+                    shell writes them as '\\'', mutt as '\"'\"' and none \
+                    leaves them alone, which is the default")
+             .takes_value(true)
+             .possible_values(&["shell", "mutt", "none"]))
+        .arg(Arg::with_name("path-separator")
+             .long("path-separator")
+             .value_name("STR")
+             .help("Put STR between the folders of relative paths in the \
+                    output instead of a slash, like . or :")
+             .takes_value(true))
+        .arg(Arg::with_name("shell-escape")
+             .long("shell-escape")
+             .conflicts_with("escape")
+             .help("Escape single quotes in maildir paths as '\\'', the same \
+                    as --escape shell"))
+        .arg(Arg::with_name("count")
+             .long("count")
+             .help("Count the messages in each maildir, for {count} in the \
+                    format or a count field in JSON. This reads every cur and \
+                    new directory, which is slow on large stores. Implied by \
+                    {count} in the format"))
+        .arg(Arg::with_name("unread")
+             .long("unread")
+             .help("Count the unread messages in each maildir, for {unread} \
+                    in the format or an unread field in JSON. Like --count, \
+                    this is slow on large stores. Implied by {unread} in the \
+                    format"))
+        .arg(Arg::with_name("size")
+             .long("size")
+             .help("Add up the size of the messages in each maildir, for \
+                    {size} in the format or a size field in JSON. Like \
+                    --count, this is slow on large stores. Implied by {size} \
+                    in the format"))
+        .arg(Arg::with_name("flags")
+             .long("flags")
+             .help("Count the flagged and replied to messages in each \
+                    maildir, for {flagged} and {replied} in the format or \
+                    flagged and replied fields in JSON. Like --count, this \
+                    is slow on large stores. Implied by {flagged} or \
+                    {replied} in the format"))
+        .arg(Arg::with_name("human")
+             .long("human")
+             .help("Show {size} in KiB, MiB and so on instead of bytes"))
+        .arg(Arg::with_name("count-only")
+             .long("count-only")
+             .help("Only output the number of maildirs that would be listed"))
+        .arg(Arg::with_name("json")
+             .long("json")
+             .help("Output the maildirs as a JSON array"))
+        .arg(Arg::with_name("json-lines")
+             .long("json-lines")
+             .conflicts_with("json")
+             .help("Output each maildir as a JSON object on its own line, \
+                    writing each as soon as it's ready instead of building \
+                    the whole array. The order is the same as the other \
+                    outputs, initial maildirs first, so nothing is written \
+                    until the walk and sorting are done"))
+        .arg(Arg::with_name("notmuch")
+             .long("notmuch")
+             .help("Output each maildir as a notmuch folder:\"...\" query, \
+                    joined with or unless --separator is given. notmuch wants \
+                    paths relative to its database root, so set \
+                    --relative-to to it if it isn't the base")
+             .conflicts_with_all(&["format", "prefix", "suffix",
+                                   "template-file", "mailboxes", "named",
+                                   "absolute", "with-cur", "maildir++",
+                                   "escape", "shell-escape", "print0",
+                                   "json", "json-lines"]))
+        .arg(Arg::with_name("maildir++")
+             .long("maildir++")
+             .help("Display Maildir++ folders like .Lists.rust as \
+                    Lists/rust. A dot escaped as \\. stays a dot"))
+        .arg(Arg::with_name("dedup-names")
+             .long("dedup-names")
+             .help("Number maildirs that would be shown with the same path as \
+                    an earlier one, like .Work.Inbox and Work/Inbox with \
+                    --maildir++, or with the same label under --named"))
+        .arg(Arg::with_name("separator")
+             .long("separator")
+             .value_name("STR")
+             .help("Separator between output entries, a space by default. \
+                    \\n and \\t are understood")
+             .takes_value(true))
+        .arg(Arg::with_name("output")
+             .short("o")
+             .long("output")
+             .value_name("PATH")
+             .help("Write the output to PATH instead of stdout. It's written \
+                    to a temporary file first, then moved into place, so PATH \
+                    is never left half written. With --watch, PATH is \
+                    replaced each time")
+             .takes_value(true))
+        .arg(Arg::with_name("select")
+             .long("select")
+             .help("Pick one of the maildirs with fzf and only output that. \
+                    When stdin isn't a terminal, they're all output")
+             .conflicts_with("watch"))
+        .arg(Arg::with_name("color")
+             .long("color")
+             .value_name("WHEN")
+             .help("Whether to colour messages on stderr. auto colours them \
+                    when stderr is a terminal and NO_COLOR isn't set. The \
+                    output itself is never coloured")
+             .takes_value(true)
+             .possible_values(&["auto", "always", "never"])
+             .default_value("auto"))
+        .arg(Arg::with_name("check")
+             .long("check")
+             .help("Check the bases for maildirs missing cur, new or tmp, cur \
+                    directories on their own, directories that can't be read \
+                    and maildirs with no messages. Each problem is reported \
+                    on stderr instead of listing the maildirs, and the exit \
+                    code is 4 if there were any"))
+        .arg(Arg::with_name("time")
+             .long("time")
+             .help("Report how long finding and sorting the maildirs took on \
+                    stderr, to help tune --jobs and --cache"))
+        .arg(Arg::with_name("fail-on-empty")
+             .long("fail-on-empty")
+             .help("When no maildirs are found, fail with an error instead \
+                    of printing an empty list. Either way the exit code is 3"))
+        .arg(Arg::with_name("no-newline")
+             .long("no-newline")
+             .help("Don't end the output with a newline, so it can be put in \
+                    the middle of a line"))
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .help("Keep running after the output, printing the list again \
+                    whenever maildirs are added or removed under the bases")
+             .conflicts_with("count-only"))
+        .arg(Arg::with_name("watch-delimiter")
+             .long("watch-delimiter")
+             .value_name("STR")
+             .help("Write STR before each list printed again by --watch, a \
+                    form feed and a newline by default. \\n, \\t and \\f are \
+                    understood")
+             .takes_value(true)
+             .requires("watch"))
+        .arg(Arg::with_name("group-separator")
+             .long("group-separator")
+             .value_name("STR")
+             .help("Add STR as an entry between maildirs with different first \
+                    path components, and after the initial ones. With \
+                    --mailboxes or --named, each group gets its own command \
+                    and STR goes on a line between them. \\n and \\t are \
+                    understood")
+             .takes_value(true)
+             .conflicts_with("print0"))
+        .arg(Arg::with_name("null")
+             .long("null")
+             .help("Separate output entries with NUL and omit the trailing \
+                    newline"))
+        .arg(Arg::with_name("print0")
+             .long("print0")
+             .help("Terminate every output entry with NUL, for xargs -0. \
+                    Entries are bare paths unless --format is given. Can't \
+                    be used with --separator")
+             .conflicts_with_all(&["separator", "null", "mailboxes", "named"]))
+        .arg(Arg::with_name("config")
+             .long("config")
+             .value_name("PATH")
+             .help("Config file setting base, initial and exclude. Defaults \
+                    to $XDG_CONFIG_HOME/mutt-maildirs/config.toml. Bases on \
+                    the command line replace the config's, initial and \
+                    exclude values are added after the command line's")
+             .takes_value(true))
+        .arg(Arg::with_name("from-muttrc")
+             .long("from-muttrc")
+             .value_name("PATH")
+             .help("Muttrc to take the base from its folder setting and \
+                    initial maildirs from its mailboxes lines. The folder is \
+                    used when no base is given on the command line, the \
+                    mailboxes are added after the other initial values")
+             .takes_value(true))
+        .arg(Arg::with_name("set")
+             .long("set")
+             .value_name("KEY=VALUE")
+             .help("Set the argument named KEY, like --set limit=5 for \
+                    --limit 5. A VALUE of true or false turns a flag on or \
+                    leaves it off, and sort is short for sort-key")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("completions")
+             .long("completions")
+             .value_name("SHELL")
+             .help("Write a completion script for SHELL to stdout")
+             .takes_value(true)
+             .possible_values(&Shell::variants()))
+}
 
-    let initial = match matches.values_of("initial") {
-        None    => vec![],
-        Some(x) => x.map(PathBuf::from).collect::<Vec<PathBuf>>(),
+// Prints the maildirs as a JSON array.
+// With `absolute`, each path is the full path to the maildir.
+fn print_json(out: &mut dyn Write,
+              maildirs: &[Maildir],
+              absolute: bool)
+              -> io::Result<()> {
+    let mut maildirs = maildirs.to_vec();
+
+    if absolute {
+        for maildir in &mut maildirs {
+            maildir.path = maildir.full_path.clone();
+        }
+    }
+
+    serde_json::to_writer(&mut *out, &maildirs)?;
+    writeln!(out)
+}
+
+// Prints each maildir as a line of JSON.
+fn print_json_lines(out: &mut dyn Write,
+                    maildirs: &[Maildir],
+                    absolute: bool)
+                    -> io::Result<()> {
+    for maildir in maildirs {
+        if absolute {
+            let mut maildir = maildir.clone();
+            maildir.path = maildir.full_path.clone();
+            serde_json::to_writer(&mut *out, &maildir)?;
+        } else {
+            serde_json::to_writer(&mut *out, maildir)?;
+        }
+
+        writeln!(out)?;
+        out.flush()?;
+    }
+
+    Ok(())
+}
+
+// The files the text output is built with, read before any of it is written
+// so a bad one doesn't leave it half done.
+struct OutputFiles {
+    labels: HashMap<PathBuf, String>,
+    accounts: Option<Vec<(PathBuf, String)>>,
+}
+
+impl OutputFiles {
+    fn load(matches: &ArgMatches,
+            error_unset: bool)
+            -> Result<OutputFiles, Box<dyn Error>> {
+        let labels = match matches.value_of("label-map") {
+            None    => HashMap::new(),
+            Some(p) => read_label_map(&expand_path(p, error_unset)?)?,
+        };
+
+        let accounts = match matches.value_of("account-file") {
+            None    => None,
+            Some(p) => Some(read_mapping(&expand_path(p, error_unset)?)?),
+        };
+
+        Ok(OutputFiles {
+            labels,
+            accounts,
+        })
+    }
+}
+
+// Writes out the maildirs in the format asked for on the command line.
+fn output(out: &mut dyn Write,
+          matches: &ArgMatches,
+          maildirs: &[Maildir],
+          template: &Template,
+          files: &OutputFiles)
+          -> io::Result<()> {
+    if matches.is_present("count-only") {
+        writeln!(out, "{}", maildirs.len())
+    } else if matches.is_present("json") {
+        print_json(out, maildirs, matches.is_present("absolute"))
+    } else if matches.is_present("json-lines") {
+        print_json_lines(out, maildirs, matches.is_present("absolute"))
+    } else {
+        print_text(out, matches, maildirs, template, files)
+    }
+}
+
+// Lets the user pick one of the maildirs with fzf, which is shown their
+// paths. Nothing is picked if they back out.
+fn select(maildirs: Vec<Maildir>) -> Result<Vec<Maildir>, Box<dyn Error>> {
+    let child = Command::new("fzf")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn();
+
+    let mut child = match child {
+        Err(e) => return Err(format!("Couldn't run fzf: {}", e).into()),
+        Ok(c)  => c,
     };
 
-    let excludes = match matches.values_of("exclude") {
-        None    => vec![],
-        Some(x) => x.map(PathBuf::from).collect::<Vec<PathBuf>>(),
+    // fzf reads its keys from the terminal, stdin is for the choices.
+    if let Some(mut stdin) = child.stdin.take() {
+        for maildir in &maildirs {
+            writeln!(stdin, "{}", maildir.path.display())?;
+        }
+    }
+
+    let picked = child.wait_with_output()?;
+    let picked = String::from_utf8_lossy(&picked.stdout);
+    let picked = picked.trim_end_matches(['\n', '\r']);
+
+    let selected = maildirs
+        .into_iter()
+        .filter(|m| m.path.display().to_string() == picked)
+        .take(1)
+        .collect();
+
+    Ok(selected)
+}
+
+// Writes the output to a temporary file next to `path`, which then replaces
+// it with the same permissions. Nothing reading `path` sees it half written,
+// and it's left alone if anything goes wrong.
+fn output_file<F>(path: &Path, write: F) -> Result<(), Box<dyn Error>>
+    where F: FnOnce(&mut dyn Write) -> io::Result<()> {
+    let name = match path.file_name() {
+        None    => {
+            let message = format!("Can't write to {}", path.display());
+            return Err(message.into());
+        },
+        Some(n) => n.to_string_lossy(),
+    };
+
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+
+    let written = File::create(&temp).and_then(|mut file| {
+        write(&mut file)?;
+
+        // Replacing the file shouldn't change who can read it.
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+
+        file.sync_all()
+    });
+
+    let written = written.and_then(|_| fs::rename(&temp, path));
+
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        let message = format!("Couldn't write {}: {}", path.display(), e);
+        return Err(message.into());
+    }
+
+    Ok(())
+}
+
+// Watches the bases for changes, calling `relist` for the maildirs after
+// each one and `output` when that's a different set from the last time.
+// Events arriving close together, like those from moving a folder full of
+// mail, are handled together.
+fn watch<L, O>(bases: &[Base],
+               mut listed: Vec<Maildir>,
+               relist: L,
+               output: O)
+               -> Result<(), Box<dyn Error>>
+    where L: Fn() -> Result<Vec<Maildir>, Box<dyn Error>>,
+          O: Fn(&[Maildir]) {
+    const DEBOUNCE: Duration = Duration::from_millis(500);
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+
+    for base in bases {
+        if let Err(e) = watcher.watch(&base.path, RecursiveMode::Recursive) {
+            let message = format!("Couldn't watch {}: {}",
+                                  base.path.display(),
+                                  e);
+            return Err(message.into());
+        }
+    }
+
+    for event in &receiver {
+        let event = match event {
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            },
+            Ok(e)  => e,
+        };
+
+        // Only something being created, removed or renamed can change the
+        // maildirs.
+        match event.kind {
+            EventKind::Create(_) | EventKind::Remove(_) => {},
+            EventKind::Modify(notify::event::ModifyKind::Name(_)) => {},
+            _ => continue,
+        }
+
+        while receiver.recv_timeout(DEBOUNCE).is_ok() {}
+
+        let maildirs = relist()?;
+        let changed = maildirs.len() != listed.len() ||
+            maildirs.iter().zip(&listed).any(|(a, b)| a.path != b.path);
+
+        if changed {
+            debug!("Maildirs changed, listing again");
+            output(&maildirs);
+            listed = maildirs;
+        }
+    }
+
+    Ok(())
+}
+
+// Gets the group a maildir belongs to when grouping the output. Initial
+// maildirs are a group of their own, the rest are grouped by the first
+// component of their path, such as the account.
+fn output_group<'a>(maildir: &'a Maildir) -> Option<Component<'a>> {
+    if maildir.initial {
+        None
+    } else {
+        maildir.path.components().next()
+    }
+}
+
+// Splits the entries for the maildirs into groups, starting a new one
+// whenever a maildir is in a different group to the one before it.
+fn grouped(maildirs: &[Maildir], entries: Vec<String>) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = Vec::new();
+
+    for (i, entry) in entries.into_iter().enumerate() {
+        let same = i > 0 &&
+                   output_group(&maildirs[i]) == output_group(&maildirs[i - 1]);
+
+        match groups.last_mut() {
+            Some(group) if same => group.push(entry),
+            _                   => groups.push(vec![entry]),
+        }
+    }
+
+    groups
+}
+
+// Joins the formatted entries for the maildirs into a single list, with any
+// group separators and the mailboxes command asked for.
+fn join_entries(matches: &ArgMatches,
+                maildirs: &[Maildir],
+                entries: Vec<String>)
+                -> String {
+    let separator = if matches.is_present("null") {
+        String::from("\0")
+    } else {
+        // notmuch folder queries are joined into one query by default.
+        let default = if matches.is_present("notmuch") { " or " } else { " " };
+        unescape(matches.value_of("separator").unwrap_or(default))
+    };
+
+    let group_separator = matches.value_of("group-separator").map(unescape);
+    let groups = match group_separator {
+        None    => vec![entries],
+        Some(_) => grouped(maildirs, entries),
+    };
+    let group_separator = group_separator.unwrap_or_default();
+
+    let command = if matches.is_present("mailboxes") {
+        Some("mailboxes")
+    } else if matches.is_present("named") {
+        Some("named-mailboxes")
+    } else {
+        None
     };
 
-    // Unwrap here is safe since base is a required argument.
-    let maildir_base = matches.value_of("base").unwrap();
+    let command = match command {
+        None          => {
+            let between = format!("{}{}{}",
+                                  separator,
+                                  group_separator,
+                                  separator);
+
+            return groups
+                .iter()
+                .map(|g| g.join(&separator))
+                .collect::<Vec<String>>()
+                .join(&between);
+        },
+        Some(command) => command,
+    };
+
+    // mutt doesn't accept a mailboxes command with nothing after it, so an
+    // empty list stays empty. A long list can be split over several
+    // commands, for versions of mutt that limit the length of a line. Each
+    // group gets commands of its own, with the group separator on a line
+    // between them, since mutt can't read it in the middle of a command.
+    let between = format!("\n{}\n", group_separator.trim_matches('\n'));
+
+    groups
+        .iter()
+        .filter(|g| !g.is_empty())
+        .map(|group| {
+            let wrap = optional_value(matches, "wrap")
+                .filter(|&n| n > 0)
+                .unwrap_or(group.len());
 
-    // Get mail directory list.
-    let maildirs = list_maildirs(&maildir_base, &initial, &excludes);
+            group
+                .chunks(wrap)
+                .map(|c| format!("{} {}", command, c.join(&separator)))
+                .collect::<Vec<String>>()
+                .join("\n")
+        })
+        .collect::<Vec<String>>()
+        .join(&between)
+}
+
+// Splits the maildirs between the accounts, given as the folder holding each
+// account's maildirs and its name. Accounts come in the order given, without
+// any that have no maildirs, and maildirs outside all of them are put in an
+// account of their own at the end.
+fn by_account(maildirs: &[Maildir],
+              accounts: &[(PathBuf, String)])
+              -> Vec<(String, Vec<Maildir>)> {
+    let mut grouped = accounts
+        .iter()
+        .map(|(_, name)| (name.clone(), Vec::new()))
+        .collect::<Vec<(String, Vec<Maildir>)>>();
+    let mut other = Vec::new();
+
+    for maildir in maildirs {
+        let account = accounts
+            .iter()
+            .position(|(dir, _)| maildir.path.starts_with(dir));
+
+        match account {
+            None    => other.push(maildir.clone()),
+            Some(i) => grouped[i].1.push(maildir.clone()),
+        }
+    }
+
+    grouped.push((String::from("Other"), other));
+    grouped.retain(|g| !g.1.is_empty());
+    grouped
+}
 
-    // Finally generate the output.
+// Prints the maildirs in the requested text format.
+fn print_text(out: &mut dyn Write,
+              matches: &ArgMatches,
+              maildirs: &[Maildir],
+              template: &Template,
+              files: &OutputFiles)
+              -> io::Result<()> {
+    // Generate the output.
     // Iterate over the maildirs
-    // .. wrap each one with the correct formatting for mutt.
+    // .. wrap each one with the output format, mutt's by default.
     // .. collect the map output into a vector of strings
-    // .. join that vector into a single string with entries seperated by space.
-    let output = maildirs
+    // .. join that vector into a single string with entries seperated by the
+    //    separator.
+    // Unwrap here is safe since cur-name has a default value.
+    let subdir = if matches.is_present("with-cur") {
+        Some(matches.value_of("cur-name").unwrap().to_owned())
+    } else {
+        None
+    };
+
+    let strip_suffixes = match matches.values_of("strip-suffix") {
+        None    => vec![],
+        Some(x) => x.map(String::from).collect::<Vec<String>>(),
+    };
+
+    let escape = match matches.value_of("escape") {
+        Some("shell") => Escape::Shell,
+        Some("mutt")  => Escape::Mutt,
+        Some(_)       => Escape::None,
+        None          => {
+            if matches.is_present("notmuch") {
+                Escape::Notmuch
+            } else if matches.is_present("shell-escape") {
+                Escape::Shell
+            } else {
+                Escape::None
+            }
+        },
+    };
+
+    let mut output_format = OutputFormat {
+        template:        template.entry.clone(),
+        escape,
+        maildirpp:       matches.is_present("maildir++"),
+        absolute:        matches.is_present("absolute"),
+        named:           matches.is_present("named"),
+        labels:          files.labels.clone(),
+        human:           matches.is_present("human"),
+        subdir,
+        renames:         HashMap::new(),
+        unix_separators: cfg!(windows) ||
+                         matches.is_present("unix-separators"),
+        strip_suffixes,
+        path_separator:  matches.value_of("path-separator").map(unescape),
+    };
+
+    // Maildirs that look the same in the output are always pointed out, and
+    // numbered after the first if asked.
+    let mut renames = HashMap::new();
+    for (name, group) in format::duplicate_names(maildirs, &output_format) {
+        let paths = group
+            .iter()
+            .map(|m| m.path.display().to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        warn!("{} are all shown as {}", paths, name);
+
+        if matches.is_present("dedup-names") {
+            for (i, maildir) in group.iter().enumerate().skip(1) {
+                let rename = format!("{} ({})", name, i + 1);
+                renames.insert(maildir.path.clone(), rename);
+            }
+        }
+    }
+    output_format.renames = renames;
+
+    // Named entries keep their real paths, so it's their labels that can
+    // look the same instead.
+    if output_format.named {
+        let mut labels = output_format.labels.clone();
+        for (label, group) in format::duplicate_labels(maildirs,
+                                                       &output_format) {
+            let paths = group
+                .iter()
+                .map(|m| m.path.display().to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+
+            warn!("{} are all labelled {}", paths, label);
+
+            if matches.is_present("dedup-names") {
+                for (i, maildir) in group.iter().enumerate().skip(1) {
+                    let rename = format!("{} ({})", label, i + 1);
+                    labels.insert(maildir.path.clone(), rename);
+                }
+            }
+        }
+        output_format.labels = labels;
+    }
+
+    let entries = maildirs
         .iter()
-        .map(|m| format!("+'{}'", m.display()))
-        .collect::<Vec<String>>()
-        .join(" ");
+        .map(|m| format::format_maildir(m, &output_format));
+
+    // A template file has everything it needs in its entries.
+    if matches.is_present("template-file") {
+        let entries = entries.collect::<String>();
+        return write!(out, "{}{}{}", template.header, entries, template.footer);
+    }
+
+    // Every record is terminated rather than separated, so there's nothing
+    // else to add.
+    if matches.is_present("print0") {
+        return write!(out, "{}", entries.map(|e| e + "\0").collect::<String>());
+    }
+
+    let null = matches.is_present("null");
+
+    // Each account gets its own list under a comment naming it.
+    let output = match files.accounts {
+        None               => {
+            let entries = entries.collect::<Vec<String>>();
+            join_entries(matches, maildirs, entries)
+        },
+        Some(ref accounts) => {
+            by_account(maildirs, accounts)
+                .into_iter()
+                .map(|(account, maildirs)| {
+                    let entries = maildirs
+                        .iter()
+                        .map(|m| format::format_maildir(m, &output_format))
+                        .collect::<Vec<String>>();
+
+                    format!("# {}\n{}",
+                            account,
+                            join_entries(matches, &maildirs, entries))
+                })
+                .collect::<Vec<String>>()
+                .join("\n")
+        },
+    };
+
+    if null || matches.is_present("no-newline") {
+        write!(out, "{}", output)
+    } else {
+        writeln!(out, "{}", output)
+    }
+}
+
+fn main() {
+    let args = match expand_sets(env::args_os().collect()) {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(a)  => a,
+    };
+
+    let matches = build_cli().get_matches_from(args);
+    let error_unset = matches.is_present("error-unset");
+
+    // Unwrap here is safe since clap has checked the shell is one it knows.
+    if let Some(shell) = matches.value_of("completions") {
+        build_cli().gen_completions_to("mutt-maildirs",
+                                       shell.parse::<Shell>().unwrap(),
+                                       &mut io::stdout());
+        return;
+    }
+
+    // Unwrap here is safe since color has a default value.
+    let color = match matches.value_of("color").unwrap() {
+        "always" => WriteStyle::Always,
+        "never"  => WriteStyle::Never,
+        _        => WriteStyle::Auto,
+    };
+
+    init_logging(matches.is_present("verbose"),
+                 matches.is_present("quiet"),
+                 color);
+
+    // Only mailboxes commands are split, so wrapping anything else would
+    // quietly do nothing.
+    if matches.is_present("wrap") &&
+       !matches.is_present("mailboxes") &&
+       !matches.is_present("named") {
+        eprintln!("mutt-maildirs: --wrap needs --mailboxes or --named");
+        process::exit(1);
+    }
+
+    // Without a format, the path is quoted between the prefix and suffix.
+    // Absolute paths aren't relative to mutt's folder, so they don't get the
+    // + prefix, and NUL terminated records are for other programs, so they
+    // get bare paths, unless a prefix was explicitly asked for.
+    let notmuch = matches.is_present("notmuch");
+    let format = match matches.value_of("format") {
+        Some(f)         => f.to_owned(),
+        None if notmuch => String::from("folder:\"{}\""),
+        None            => {
+            let print0 = matches.is_present("print0");
+            let unprefixed = print0 || matches.is_present("absolute");
+            let prefix = match matches.value_of("prefix") {
+                Some(p)            => p,
+                None if unprefixed => "",
+                None               => "+",
+            };
+            let quoted = if print0 { "{}" } else { "'{}'" };
+            let suffix = matches.value_of("suffix").unwrap_or("");
+
+            format!("{}{}{}", prefix, quoted, suffix)
+        },
+    };
+
+    let template = match matches.value_of("template-file") {
+        None    => Ok(Template::entry(&format)),
+        Some(p) => {
+            expand_path(p, error_unset)
+                .and_then(|p| {
+                    match fs::read_to_string(&p) {
+                        Err(e) => {
+                            let message = format!("Couldn't read {}: {}",
+                                                  p.display(),
+                                                  e);
+                            Err(message.into())
+                        },
+                        Ok(c)  => Ok(Template::parse(&c)),
+                    }
+                })
+        },
+    };
+
+    let template = match template {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(t)  => t,
+    };
+
+    if let Err(e) = format::check_template(&template.entry) {
+        eprintln!("mutt-maildirs: {}", e);
+        process::exit(1);
+    }
+
+    let cache = matches.value_of("cache").map(|c| {
+        match expand_path(c, error_unset) {
+            Err(e) => {
+                eprintln!("mutt-maildirs: {}", e);
+                process::exit(1);
+            },
+            Ok(p)  => p,
+        }
+    });
+
+    let index = matches.value_of("index").map(|i| {
+        match expand_path(i, error_unset) {
+            Err(e) => {
+                eprintln!("mutt-maildirs: {}", e);
+                process::exit(1);
+            },
+            Ok(p)  => p,
+        }
+    });
+
+    let since = match since(&matches) {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(s)  => s,
+    };
+
+    let seen_state = matches.value_of("since-seen").map(|s| {
+        match expand_path(s, error_unset) {
+            Err(e) => {
+                eprintln!("mutt-maildirs: {}", e);
+                process::exit(1);
+            },
+            Ok(p)  => p,
+        }
+    });
+
+    let sort_keys = match matches.value_of("sort-key").map(parse_sort_keys) {
+        None         => vec![],
+        Some(Err(e)) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Some(Ok(k))  => k,
+    };
+
+    // Only the maildirs directly in a base, not the base or anything deeper.
+    let top_level = if matches.is_present("top-level-only") {
+        Some(1)
+    } else {
+        None
+    };
+
+    let options = Options {
+        only_unread:     matches.is_present("only-unread"),
+        strict:          matches.is_present("strict"),
+        max_depth:       top_level.or(optional_value(&matches, "max-depth")),
+        min_depth:       top_level.or(optional_value(&matches, "min-depth")),
+        follow_symlinks: matches.is_present("follow-symlinks"),
+        ignore_case:     matches.is_present("ignore-case"),
+        reverse:         matches.is_present("reverse"),
+        count:           matches.is_present("count") ||
+                         template.entry.contains("{count}"),
+        unread:          matches.is_present("unread") ||
+                         template.entry.contains("{unread}"),
+        exclude_empty:   matches.is_present("exclude-empty"),
+        ignore_hidden:   matches.is_present("ignore-hidden"),
+        jobs:            optional_value(&matches, "jobs").unwrap_or(1),
+        sort_initial:    matches.is_present("sort-initial"),
+        warn_unused:     matches.is_present("warn-unused") ||
+                         matches.is_present("verbose"),
+        tree:            matches.is_present("tree"),
+        min_messages:    optional_value(&matches, "min-messages"),
+        sort_mtime:      matches.is_present("sort-mtime"),
+        dir_mtime:       matches.is_present("dir-mtime"),
+        size:            matches.is_present("size") ||
+                         template.entry.contains("{size}"),
+        cur_name:        matches.value_of("cur-name").unwrap().to_owned(),
+        cache,
+        limit:           optional_value(&matches, "limit"),
+        muttignore:      matches.is_present("muttignore"),
+        sort_count:      matches.is_present("sort-count"),
+        match_basename:  matches.value_of("initial-match") == Some("basename"),
+        include_mbox:    matches.is_present("include-mbox"),
+        resolve_links:   matches.is_present("resolve-symlinks"),
+        initial_glob:    matches.is_present("initial-glob"),
+        walk_tmp:        matches.is_present("walk-tmp"),
+        since,
+        exclude_deeper:  optional_value(&matches, "exclude-deeper-than"),
+        seen_state,
+        initial_last:    matches.is_present("initial-last"),
+        sort_keys,
+        no_sort:         matches.is_present("no-sort"),
+        index,
+        flags:           matches.is_present("flags") ||
+                         template.entry.contains("{flagged}") ||
+                         template.entry.contains("{replied}"),
+    };
+
+    // An explicitly given config file has to exist, the default one doesn't.
+    let config = match matches.value_of("config") {
+        Some(path) => {
+            expand_path(path, error_unset)
+                .and_then(|p| config::load(&p))
+        },
+        None       => {
+            match config::default_path() {
+                Some(ref path) if path.exists() => config::load(path),
+                _                               => Ok(Default::default()),
+            }
+        },
+    };
+
+    let config = match config {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(c)  => c,
+    };
+
+    let muttrc = match matches.value_of("from-muttrc") {
+        Some(path) => {
+            expand_path(path, error_unset)
+                .and_then(|p| muttrc::load(&p))
+        },
+        None       => Ok(Default::default()),
+    };
+
+    let muttrc = match muttrc {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(m)  => m,
+    };
+
+    // Bases on the command line win, then the muttrc's folder, then the
+    // environment, then the config file, and finally a base piped in on
+    // stdin.
+    let mut maildir_bases = match matches.values_of("base") {
+        None    => {
+            match muttrc.folder {
+                Some(ref f) => vec![f.clone()],
+                None        => {
+                    env_base().unwrap_or_else(|| config.base.to_vec())
+                },
+            }
+        },
+        Some(x) => x.map(String::from).collect::<Vec<String>>(),
+    };
+
+    if maildir_bases.is_empty() {
+        maildir_bases = match stdin_base() {
+            Err(e) => {
+                eprintln!("mutt-maildirs: Couldn't read base from stdin: {}",
+                          e);
+                process::exit(1);
+            },
+            Ok(b)  => b,
+        };
+    }
+
+    if maildir_bases.is_empty() {
+        eprintln!("mutt-maildirs: No base given, use --base, ${}, a config \
+                   file or stdin",
+                  BASE_VAR);
+        process::exit(1);
+    }
+
+    // Canonicalized like the bases, so walked paths can be stripped of it.
+    let relative_to = matches.value_of("relative-to").map(|r| {
+        match expand_path(r, error_unset) {
+            Err(e) => {
+                eprintln!("mutt-maildirs: {}", e);
+                process::exit(1);
+            },
+            Ok(p)  => fs::canonicalize(&p).unwrap_or(p),
+        }
+    });
+
+    let labels = match matches.values_of("label") {
+        None    => vec![],
+        Some(x) => x.collect::<Vec<_>>(),
+    };
+
+    let mut bases = Vec::with_capacity(maildir_bases.len());
+    for (i, maildir_base) in maildir_bases.iter().enumerate() {
+        let path = match expand_path(maildir_base, error_unset) {
+            Err(e) => {
+                eprintln!("mutt-maildirs: {}", e);
+                process::exit(1);
+            },
+            Ok(b)  => b,
+        };
+
+        // Canonicalizing gets rid of any . and .. components, so stripping
+        // the base from walked paths works. A base that doesn't exist is
+        // reported when listing the maildirs.
+        let path = fs::canonicalize(&path).unwrap_or(path);
 
-    println!("{}", output);
+        info!("Expanded base to {}", path.display());
+
+        // A single base needs no label to keep its maildirs distinct, so it
+        // only gets one if it was asked for. Paths relative to a directory
+        // above the base already hold the base's name, so only an explicit
+        // label is added to them, but those relative to one inside it can
+        // still have the name prepended.
+        let prepend_base = matches.is_present("prepend-base");
+        let prepend = match relative_to {
+            None        => maildir_bases.len() > 1 || prepend_base,
+            Some(ref r) => prepend_base && r.starts_with(&path),
+        };
+
+        let label = match labels.get(i) {
+            Some(l)         => Some(PathBuf::from(l)),
+            None if prepend => path.file_name().map(PathBuf::from),
+            None            => None,
+        };
+
+        bases.push(Base {
+            path,
+            label,
+            relative_to: relative_to.clone(),
+        });
+    }
+
+    // Different bases with the same label would have their maildirs listed
+    // under the same names.
+    let mut labelled = HashMap::new();
+    for base in &bases {
+        let label = match base.label {
+            None        => continue,
+            Some(ref l) => l,
+        };
+
+        match labelled.insert(label, &base.path) {
+            Some(p) if *p != base.path => {
+                warn!("More than one base is labelled {}, give them each a \
+                       --label to tell their maildirs apart",
+                      label.display());
+            },
+            _                          => {},
+        }
+    }
+
+    // Initial and exclude values get the same expansion as the bases, and
+    // are then made relative to them.
+    let relative = |v: &str| base_relative(&bases, v, error_unset);
+
+    let initial = list_values(&matches,
+                              "initial",
+                              "initial-file",
+                              error_unset)
+        .and_then(|mut v| {
+            if let Some(path) = matches.value_of("mru-file") {
+                let path = expand_path(path, error_unset)?;
+                v.extend(read_mru(&path)?);
+            }
+
+            Ok(v)
+        })
+        .map(|mut v| {
+            v.extend(config.initial.iter().cloned());
+            v
+        })
+        .and_then(|v| v.iter().map(|v| relative(v)).collect())
+        .and_then(|mut v: Vec<PathBuf>| {
+            // A muttrc's mailboxes often include the folder itself, which is
+            // the base rather than a maildir under it.
+            for mailbox in &muttrc.mailboxes {
+                let mailbox = relative(mailbox)?;
+
+                if !mailbox.as_os_str().is_empty() {
+                    v.push(mailbox);
+                }
+            }
+
+            Ok(v)
+        });
+
+    let initial: Vec<PathBuf> = match initial {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(i)  => i,
+    };
+
+    // Patterns are checked up front, since a bad one would otherwise just
+    // match nothing.
+    if options.initial_glob {
+        for pattern in &initial {
+            if let Err(e) = Pattern::new(&pattern.to_string_lossy()) {
+                eprintln!("mutt-maildirs: Invalid initial pattern: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let excludes = list_values(&matches,
+                               "exclude",
+                               "exclude-file",
+                               error_unset)
+        .and_then(|mut v| {
+            if matches.is_present("exclude-stdin") {
+                let stdin = io::stdin();
+                v.extend(read_lines(stdin.lock())?);
+            }
+            Ok(v)
+        })
+        .map(|mut v| {
+            v.extend(config.exclude.iter().cloned());
+            v
+        })
+        .and_then(|v| v.iter().map(|v| relative(v)).collect());
+
+    let excludes: Vec<PathBuf> = match excludes {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(e)  => e,
+    };
+
+    let patterns = excludes
+        .iter()
+        .flat_map(|p| expand_braces(&p.to_string_lossy()))
+        .map(|p| Pattern::new(&p))
+        .collect::<Result<Vec<Pattern>, _>>();
+
+    let patterns = match patterns {
+        Err(e) => {
+            eprintln!("mutt-maildirs: Invalid exclude pattern: {}", e);
+            process::exit(1);
+        },
+        Ok(p)  => p,
+    };
+
+    let regexes = match matches.values_of("exclude-regex") {
+        None    => vec![],
+        Some(x) => {
+            match x.map(Regex::new).collect::<Result<Vec<Regex>, _>>() {
+                Err(e) => {
+                    eprintln!("mutt-maildirs: Invalid exclude regex: {}", e);
+                    process::exit(2);
+                },
+                Ok(r)  => r,
+            }
+        },
+    };
+
+    let prefixes = match matches.values_of("exclude-prefix") {
+        None    => Ok(vec![]),
+        Some(x) => x.map(&relative).collect(),
+    };
+
+    let prefixes = match prefixes {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(p)  => p,
+    };
+
+    let includes = match matches.values_of("include") {
+        None    => Ok(vec![]),
+        Some(x) => x.map(&relative).collect(),
+    };
+
+    let includes: Vec<PathBuf> = match includes {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(i)  => i,
+    };
+
+    let includes = includes
+        .iter()
+        .flat_map(|p| expand_braces(&p.to_string_lossy()))
+        .map(|p| Pattern::new(&p))
+        .collect::<Result<Vec<Pattern>, _>>();
+
+    let includes = match includes {
+        Err(e) => {
+            eprintln!("mutt-maildirs: Invalid include pattern: {}", e);
+            process::exit(1);
+        },
+        Ok(i)  => i,
+    };
+
+    let excludes = Excludes {
+        patterns,
+        regexes,
+        prefixes,
+        includes,
+    };
+
+    // Checking replaces the usual output.
+    if matches.is_present("check") {
+        let problems = check_maildirs(&bases, &options);
+
+        for problem in &problems {
+            eprintln!("mutt-maildirs: {}", problem);
+        }
+
+        // Problems get their own code, so they can be told from an error.
+        process::exit(if problems.is_empty() { 0 } else { 4 });
+    }
+
+    // Get mail directory list, timing it if asked.
+    let started = Instant::now();
+    let maildirs = match list_maildirs(&bases,
+                                       &initial,
+                                       &excludes,
+                                       &options) {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(m)  => m,
+    };
+
+    if matches.is_present("time") {
+        eprintln!("mutt-maildirs: Listed {} maildirs in {}ms",
+                  maildirs.len(),
+                  started.elapsed().as_millis());
+    }
+
+    if maildirs.is_empty() && matches.is_present("fail-on-empty") {
+        eprintln!("mutt-maildirs: No maildirs found");
+        process::exit(3);
+    }
+
+    // Picking needs someone at the terminal, otherwise everything's listed.
+    let maildirs = if matches.is_present("select") && io::stdin().is_terminal()
+    {
+        match select(maildirs) {
+            Err(e) => {
+                eprintln!("mutt-maildirs: {}", e);
+                process::exit(1);
+            },
+            Ok(m)  => m,
+        }
+    } else {
+        maildirs
+    };
+
+    let output_path = matches.value_of("output").map(|o| {
+        match expand_path(o, error_unset) {
+            Err(e) => {
+                eprintln!("mutt-maildirs: {}", e);
+                process::exit(1);
+            },
+            Ok(p)  => p,
+        }
+    });
+
+    let output_files = match OutputFiles::load(&matches, error_unset) {
+        Err(e) => {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        },
+        Ok(f)  => f,
+    };
+
+    // The output file is replaced each time, anything else is written to an
+    // existing output.
+    let write_output = |maildirs: &[Maildir], delimiter: &str| {
+        let written = match output_path {
+            Some(ref path) => {
+                output_file(path, |out| {
+                    output(out, &matches, maildirs, &template, &output_files)
+                })
+            },
+            None           => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+
+                write!(stdout, "{}", delimiter)
+                    .and_then(|_| {
+                        output(&mut stdout,
+                               &matches,
+                               maildirs,
+                               &template,
+                               &output_files)
+                    })
+                    .and_then(|_| stdout.flush())
+                    .map_err(Box::from)
+            },
+        };
+
+        if let Err(e) = written {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        }
+    };
+
+    write_output(&maildirs, "");
+
+    // Watching only stops when something goes wrong.
+    if matches.is_present("watch") {
+        let delimiter = matches
+            .value_of("watch-delimiter")
+            .map_or_else(|| "\x0c\n".to_owned(), unescape);
+
+        let relist = || list_maildirs(&bases, &initial, &excludes, &options);
+        let reprint = |maildirs: &[Maildir]| write_output(maildirs, &delimiter);
+
+        if let Err(e) = watch(&bases, maildirs.clone(), relist, reprint) {
+            eprintln!("mutt-maildirs: {}", e);
+            process::exit(1);
+        }
+    }
+
+    // The output is still printed when nothing was found, since mutt expects
+    // it, but the exit code lets scripts tell.
+    if maildirs.is_empty() {
+        process::exit(3);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Expands the --set arguments in `args`, as strings.
+    fn sets(args: &[&str]) -> Result<Vec<String>, Box<dyn Error>> {
+        let args = args.iter().map(OsString::from).collect();
+
+        expand_sets(args).map(|a| {
+            a.into_iter()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect()
+        })
+    }
+
+    #[test]
+    fn escapes_in_separators() {
+        assert_eq!(unescape(r"a\nb\tc\fd"), "a\nb\tc\x0cd");
+        assert_eq!(unescape(r"X\\nY"), "X\\nY");
+        assert_eq!(unescape(r"\\\\"), "\\\\");
+        assert_eq!(unescape(r"a\b\"), "a\\b\\");
+    }
+
+    #[test]
+    fn sets_become_arguments() {
+        assert_eq!(sets(&["--set", "reverse=true", "--set=limit=5"]).unwrap(),
+                   ["--reverse", "--limit=5"]);
+        assert_eq!(sets(&["-b", "~/Mail", "--set", "ignore-case=false"])
+                       .unwrap(),
+                   ["-b", "~/Mail"]);
+    }
+
+    #[test]
+    fn set_keys() {
+        assert_eq!(sets(&["--set", "only_unread=true"]).unwrap(),
+                   ["--only-unread"]);
+        assert_eq!(sets(&["--set", " sort = unread:desc "]).unwrap(),
+                   ["--sort-key=unread:desc"]);
+    }
+
+    #[test]
+    fn bad_sets() {
+        assert!(sets(&["--set"]).is_err());
+        assert!(sets(&["--set", "reverse"]).is_err());
+        assert!(sets(&["--set", "no-such-flag=true"]).is_err());
+        assert!(sets(&["--set", "set=reverse=true"]).is_err());
+    }
 }
@@ -0,0 +1,318 @@
+/*
+ * Reading the base and initial maildirs from a muttrc
+ */
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// The settings taken from a muttrc.
+#[derive(Default)]
+pub struct Muttrc {
+    pub folder: Option<String>,
+    pub mailboxes: Vec<String>,
+}
+
+// A word of a muttrc line, and whether it ended with a closing quote. That
+// tells `folder=""` apart from `folder=` with the value in the next word.
+struct Word {
+    text: String,
+    quoted: bool,
+}
+
+impl Word {
+    fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
+// Splits a muttrc line into words, the way mutt does. Quotes group words
+// together and are removed, a backslash escapes the next character outside
+// of single quotes, and a # outside of quotes starts a comment. A command in
+// backticks is left as it is, backticks included.
+fn words(line: &str) -> Vec<Word> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        let closing = quote == Some(c);
+        match (quote, c) {
+            (Some(q), c) if c == q            => quote = None,
+            (Some('"'), '\\') | (None, '\\')  => {
+                if let Some(c) = chars.next() {
+                    word.push(c);
+                }
+                in_word = true;
+            },
+            (Some(_), c)                      => word.push(c),
+            (None, '\'') | (None, '"')        => {
+                quote = Some(c);
+                in_word = true;
+            },
+            (None, '#')                       => break,
+            // Commands run by mutt are kept whole, so they can be skipped.
+            (None, '`')                       => {
+                word.push(c);
+                for c in chars.by_ref() {
+                    word.push(c);
+                    if c == '`' {
+                        break;
+                    }
+                }
+                in_word = true;
+            },
+            (None, c) if c.is_whitespace()    => {
+                if in_word {
+                    words.push(Word {
+                        text: word.split_off(0),
+                        quoted,
+                    });
+                    in_word = false;
+                }
+            },
+            (None, c)                         => {
+                word.push(c);
+                in_word = true;
+            },
+        }
+
+        // Only a quote closed at the very end of the word counts.
+        if in_word {
+            quoted = closing;
+        }
+    }
+
+    if in_word {
+        words.push(Word {
+            text: word,
+            quoted,
+        });
+    }
+
+    words
+}
+
+// Joins lines ending in a backslash with the line after them.
+fn logical_lines(contents: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+
+    for line in contents.lines() {
+        match line.strip_suffix('\\') {
+            Some(l) => current.push_str(l),
+            None    => {
+                current.push_str(line);
+                lines.push(current.split_off(0));
+            },
+        }
+    }
+
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    lines
+}
+
+// Strips the + or = that mutt uses for paths inside the folder. The folder
+// itself is left out, since it's the base, as are the mailboxes a command
+// would give, like the output of mutt-maildirs itself.
+fn mailbox(word: &str) -> Option<String> {
+    let path = word.trim_start_matches(['+', '=']).trim_matches('/');
+
+    if path.is_empty() || word.contains('`') {
+        None
+    } else if word.starts_with(['+', '=']) {
+        Some(path.to_string())
+    } else {
+        Some(word.to_string())
+    }
+}
+
+// Finds the value given to `name` by a `set` command, which can set more
+// than one variable, with or without spaces around the =.
+fn setting(args: &[Word], name: &str) -> Option<String> {
+    let mut args = args.iter().peekable();
+    let mut value = None;
+
+    while let Some(arg) = args.next() {
+        let text = arg.as_str();
+        let (n, v) = match text.split_once('=') {
+            // An empty value in quotes is the value, not a space before it.
+            Some((n, "")) if !arg.quoted => (n, args.next().map(Word::as_str)),
+            Some((n, v))                 => (n, Some(v)),
+            None                         => {
+                match args.peek().map(|a| a.as_str()) {
+                    Some("=")                     => {
+                        args.next();
+                        (text, args.next().map(Word::as_str))
+                    },
+                    Some(a) if a.starts_with('=') => {
+                        (text, args.next().map(Word::as_str))
+                    },
+                    _                             => continue,
+                }
+            },
+        };
+
+        // A folder worked out by a command can't be known here.
+        if n == name {
+            value = v
+                .filter(|v| !v.contains('`'))
+                .map(|v| v.trim_start_matches('=').to_string());
+        }
+    }
+
+    value
+}
+
+// Gets the mailboxes from the arguments of a `mailboxes` command, skipping
+// the options newer versions of mutt allow before each of them.
+fn mailboxes(args: &[Word]) -> Vec<String> {
+    let mut mailboxes = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-label"                => {
+                args.next();
+            },
+            a if a.starts_with('-') => {},
+            a                       => mailboxes.extend(mailbox(a)),
+        }
+    }
+
+    mailboxes
+}
+
+/// Parses the `set folder` setting and the `mailboxes` and
+/// `named-mailboxes` commands out of a muttrc. Anything else is ignored.
+pub fn parse(contents: &str) -> Muttrc {
+    let mut muttrc = Muttrc::default();
+
+    for line in logical_lines(contents) {
+        let words = words(&line);
+        let (command, args) = match words.split_first() {
+            Some((c, a)) => (c.as_str(), a),
+            None         => continue,
+        };
+
+        match command {
+            "set"             => {
+                // An empty folder leaves it unset.
+                if let Some(folder) = setting(args, "folder") {
+                    muttrc.folder = Some(folder).filter(|f| !f.is_empty());
+                }
+            },
+            "mailboxes"       => muttrc.mailboxes.extend(mailboxes(args)),
+            // These come as pairs of a description and a mailbox.
+            "named-mailboxes" => {
+                muttrc.mailboxes.extend(args
+                    .iter()
+                    .skip(1)
+                    .step_by(2)
+                    .filter_map(|a| mailbox(a.as_str())));
+            },
+            _                 => {},
+        }
+    }
+
+    muttrc
+}
+
+/// Reads the muttrc at `path`.
+pub fn load(path: &Path) -> Result<Muttrc, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+        Err(e) => {
+            let message = format!("Couldn't read {}: {}", path.display(), e);
+            Err(message.into())
+        },
+        Ok(c)  => Ok(parse(&c)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Gets the text of each word of `line`.
+    fn texts(line: &str) -> Vec<String> {
+        words(line).into_iter().map(|w| w.text).collect()
+    }
+
+    #[test]
+    fn quotes_group_words() {
+        assert_eq!(texts(r#"set folder="~/My Mail""#),
+                   ["set", "folder=~/My Mail"]);
+        assert_eq!(texts("mailboxes 'a b' \"c\\\"d\""),
+                   ["mailboxes", "a b", "c\"d"]);
+        assert_eq!(texts(r"mailboxes 'a\b' a\ b"),
+                   ["mailboxes", r"a\b", "a b"]);
+    }
+
+    #[test]
+    fn comments_are_dropped() {
+        assert_eq!(texts("mailboxes +a # +b"), ["mailboxes", "+a"]);
+        assert_eq!(texts("mailboxes '+a # b'"), ["mailboxes", "+a # b"]);
+        assert!(texts("# set folder=~/Mail").is_empty());
+    }
+
+    #[test]
+    fn backticks_are_kept_whole() {
+        assert_eq!(texts("mailboxes `find ~/Mail -type d` +a"),
+                   ["mailboxes", "`find ~/Mail -type d`", "+a"]);
+    }
+
+    #[test]
+    fn continued_lines() {
+        let muttrc = parse("mailboxes +a \\\n  +b\nmailboxes +c\n");
+
+        assert_eq!(muttrc.mailboxes, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn folder_setting() {
+        let folder = |line| parse(line).folder;
+
+        assert_eq!(folder("set folder=~/Mail"), Some("~/Mail".to_string()));
+        assert_eq!(folder("set folder = ~/Mail"), Some("~/Mail".to_string()));
+        assert_eq!(folder("set folder= ~/Mail"), Some("~/Mail".to_string()));
+        assert_eq!(folder("set sort=date folder=~/Mail"),
+                   Some("~/Mail".to_string()));
+        assert_eq!(folder("set folder=`echo ~/Mail`"), None);
+        assert_eq!(folder("set spoolfile=+INBOX"), None);
+    }
+
+    #[test]
+    fn empty_folder_setting() {
+        let muttrc = parse("set folder=~/Mail\nset folder=\"\" sort=date\n");
+
+        assert_eq!(muttrc.folder, None);
+        assert_eq!(setting(&words(r#"folder="" sort=date"#), "sort"),
+                   Some("date".to_string()));
+    }
+
+    #[test]
+    fn folder_prefixes() {
+        let muttrc = parse("mailboxes +INBOX =Lists/rust ~/Other + =/\n");
+
+        assert_eq!(muttrc.mailboxes, ["INBOX", "Lists/rust", "~/Other"]);
+    }
+
+    #[test]
+    fn mailbox_labels() {
+        let muttrc = parse("mailboxes -label Work +work -notify +a +b\n");
+
+        assert_eq!(muttrc.mailboxes, ["work", "a", "b"]);
+    }
+
+    #[test]
+    fn named_mailboxes() {
+        let muttrc = parse("named-mailboxes Work +work \"My Lists\" +lists\n");
+
+        assert_eq!(muttrc.mailboxes, ["work", "lists"]);
+    }
+}
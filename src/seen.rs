@@ -0,0 +1,37 @@
+/*
+ * Unread state between runs
+ */
+use serde_json;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The unread messages in each maildir, by their full path, as the unique
+/// part of each message's filename.
+pub type Unread = HashMap<PathBuf, HashSet<String>>;
+
+/// Reads the unread messages recorded by an earlier run from the state file
+/// at `path`. A missing or unreadable file has no messages, so every unread
+/// message is new.
+pub fn load(path: &Path) -> Unread {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Writes the unread messages to the state file at `path`.
+pub fn save(path: &Path, unread: &Unread) -> Result<(), Box<dyn Error>> {
+    let contents = serde_json::to_string(unread)?;
+
+    match fs::write(path, contents) {
+        Err(e) => {
+            let message = format!("Couldn't write seen state {}: {}",
+                                  path.display(),
+                                  e);
+            Err(message.into())
+        },
+        Ok(()) => Ok(()),
+    }
+}